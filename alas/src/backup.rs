@@ -0,0 +1,262 @@
+use rusqlite::{Connection, Transaction};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::config::Config;
+use crate::error::Error;
+
+const COLLECTION_FILE: &str = "collection.anki2";
+const CONFIG_FILE: &str = "config.json";
+
+/// Files in `.alas` which only belong to this computer and are kept out of version control.
+const GITIGNORE: &str = "# snapshots of the Anki collection and other files of this computer
+backups/
+logs/
+lock
+journal.json
+";
+
+/// Writes `.alas/.gitignore` unless the project already has one.
+pub fn write_gitignore(config: &Config) -> Result<(), Error> {
+    let path = config.alas_dir().join(".gitignore");
+    if !path.exists() {
+        fs::write(path, GITIGNORE)?;
+    }
+    Ok(())
+}
+
+/// Saves the collection and the config as a new snapshot and removes the oldest ones.
+///
+/// `conn` must hold the exclusive lock on the collection and must not be in a transaction.
+pub fn create_backup(conn: &Connection, config: &Config) -> Result<String, Error> {
+    checkpoint(conn)?;
+    let name = save_snapshot(config)?;
+    remove_obsolete_snapshots(config, None)?;
+    Ok(name)
+}
+
+/// Snapshot of the state before a sync, which is only saved once the sync commits a change.
+pub enum SyncSnapshot {
+    Disabled,
+    Pending { changes_before: i64 },
+    Saved { name: String, changes_before: i64 },
+}
+
+impl SyncSnapshot {
+    /// `conn` must hold the exclusive lock on the collection and must not be in a transaction.
+    pub fn new(conn: &Connection, config: &Config) -> Result<Self, Error> {
+        if config.backups == 0 {
+            return Ok(Self::Disabled);
+        }
+
+        checkpoint(conn)?;
+        let changes_before = total_changes(conn)?;
+
+        // with a write-ahead log the database file keeps its state until a commit is checkpointed;
+        // other journal modes can write uncommitted changes to it, so the snapshot is saved now
+        let journal_mode: String = conn.query_row("PRAGMA journal_mode", [], |row| row.get(0))?;
+        if journal_mode.eq_ignore_ascii_case("wal") {
+            Ok(Self::Pending { changes_before })
+        } else {
+            Ok(Self::Saved {
+                name: save_snapshot(config)?,
+                changes_before,
+            })
+        }
+    }
+
+    /// Saves the snapshot if the transaction about to be committed changes the collection.
+    pub fn before_commit(&mut self, trans: &Transaction, config: &Config) -> Result<(), Error> {
+        if let Self::Pending { changes_before } = *self {
+            if total_changes(trans)? != changes_before {
+                *self = Self::Saved {
+                    name: save_snapshot(config)?,
+                    changes_before,
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the snapshot if the sync changed nothing after all, otherwise the oldest ones.
+    pub fn finish(self, conn: &Connection, config: &Config) -> Result<(), Error> {
+        if let Self::Saved {
+            name,
+            changes_before,
+        } = self
+        {
+            if total_changes(conn)? == changes_before {
+                fs::remove_dir_all(config.alas_dir().join("backups").join(name))?;
+            } else {
+                remove_obsolete_snapshots(config, None)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Moves everything from the write-ahead log into the database file.
+fn checkpoint(conn: &Connection) -> Result<(), Error> {
+    conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+    Ok(())
+}
+
+/// Returns the number of rows changed on the connection so far.
+fn total_changes(conn: &Connection) -> Result<i64, Error> {
+    Ok(conn.query_row("SELECT total_changes()", [], |row| row.get(0))?)
+}
+
+/// Copies the database file and the config file to a new snapshot; the database file must contain
+/// everything committed so far.
+fn save_snapshot(config: &Config) -> Result<String, Error> {
+    let backup_dir = config.alas_dir().join("backups");
+
+    // names have a fixed length, so they sort chronologically without suffixes
+    let mut name = timestamp();
+    while backup_dir.join(&name).exists() {
+        thread::sleep(Duration::from_millis(1));
+        name = timestamp();
+    }
+
+    let snapshot_dir = backup_dir.join(&name);
+    fs::create_dir_all(&snapshot_dir)?;
+
+    // projects initialized by older versions have no .gitignore yet
+    write_gitignore(config)?;
+
+    let collection = anki_io::read_locked_db_file(config.anki_collection_path())?;
+    write_atomic(&snapshot_dir.join(COLLECTION_FILE), &collection)?;
+    write_atomic(
+        &snapshot_dir.join(CONFIG_FILE),
+        &fs::read(&config.config_path)?,
+    )?;
+
+    Ok(name)
+}
+
+/// Removes the oldest snapshots beyond the number to keep; the snapshot being restored is neither
+/// counted nor removed.
+fn remove_obsolete_snapshots(config: &Config, restored: Option<&str>) -> Result<(), Error> {
+    let backup_dir = config.alas_dir().join("backups");
+
+    // the oldest snapshots come first
+    let snapshots: Vec<String> = list_snapshots(config)?
+        .into_iter()
+        .filter(|snapshot| Some(snapshot.as_str()) != restored)
+        .collect();
+    let num_obsolete = snapshots.len().saturating_sub(config.backups.max(1));
+    for snapshot in &snapshots[..num_obsolete] {
+        fs::remove_dir_all(backup_dir.join(snapshot))?;
+    }
+    Ok(())
+}
+
+/// Puts back the collection and the config of a snapshot, the latest one by default.
+/// The current state is saved as a new snapshot first, so restoring can be undone.
+///
+/// `conn` must hold the exclusive lock on the collection and is closed before the collection
+/// is replaced. Returns the names of the restored and the new snapshot.
+pub fn restore_backup(
+    conn: Connection,
+    config: &Config,
+    snapshot: Option<&str>,
+) -> Result<(String, String), Error> {
+    let snapshots = list_snapshots(config)?;
+    let name = match snapshot {
+        Some(name) => snapshots
+            .iter()
+            .find(|s| *s == name)
+            .ok_or_else(|| Error::AlasError(format!("There is no snapshot '{}'.", name)))?,
+        None => snapshots
+            .last()
+            .ok_or_else(|| Error::AlasError("There are no snapshots.".to_string()))?,
+    }
+    .clone();
+
+    let snapshot_dir = config.alas_dir().join("backups").join(&name);
+    let collection = fs::read(snapshot_dir.join(COLLECTION_FILE))?;
+    let config_data = fs::read(snapshot_dir.join(CONFIG_FILE))?;
+
+    // the write-ahead log must not be applied to the restored collection
+    checkpoint(&conn)?;
+    let backup = save_snapshot(config)?;
+    remove_obsolete_snapshots(config, Some(&name))?;
+    conn.close().map_err(|(_, err)| Error::DbError(err))?;
+
+    let collection_path = config.anki_collection_path();
+    for suffix in ["-wal", "-shm"] {
+        let mut path = collection_path.clone().into_os_string();
+        path.push(suffix);
+        let path = PathBuf::from(path);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+
+    write_atomic(&collection_path, &collection)?;
+    write_atomic(&config.config_path, &config_data)?;
+
+    Ok((name, backup))
+}
+
+/// Returns the names of all snapshots, the oldest first.
+pub fn list_snapshots(config: &Config) -> Result<Vec<String>, Error> {
+    let backup_dir = config.alas_dir().join("backups");
+    if !backup_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut snapshots = Vec::new();
+    for entry in fs::read_dir(backup_dir)? {
+        let entry = entry?;
+        if entry.path().join(COLLECTION_FILE).exists() {
+            snapshots.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+
+    // timestamps sort chronologically
+    snapshots.sort();
+    Ok(snapshots)
+}
+
+fn write_atomic(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let mut file = anki_io::new_tempfile_in_parent_of(path)?;
+    std::io::Write::write_all(&mut file, data)?;
+    anki_io::atomic_rename(file, path, true)?;
+    Ok(())
+}
+
+/// Returns the current UTC time like `20240131-235959-999`.
+fn timestamp() -> String {
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64);
+    let secs = millis / 1000;
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+
+    // convert days since 1970-01-01 to a civil date
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}-{:03}",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        millis % 1000
+    )
+}
@@ -1,12 +1,25 @@
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fs,
+    io::Write,
     path::{Path, PathBuf},
 };
 
+use serde_json::{Map, Value};
+
+use crate::deckoptions::DeckOptions;
 use crate::error::Error;
-use crate::note::Note;
+use crate::note::{Note, NoteKind, OutputMode};
+use crate::renderer::{ImageFormat, ImageOutput, RendererKind};
+use crate::subdeck::SubdeckMode;
+use crate::theme::SvgTheme;
+
+/// Version of the config format written by this binary.
+const CONFIG_VERSION: u64 = 1;
+
+/// Migrations of the config format; the migration at index `i` upgrades version `i` to `i + 1`.
+const MIGRATIONS: [fn(&mut Map<String, Value>); CONFIG_VERSION as usize] = [migrate_v0_to_v1];
 
 pub enum NoteState {
     Unchanged,
@@ -20,14 +33,64 @@ pub struct Config {
     pub config_path: PathBuf,
     #[serde(skip)]
     pub synced_notes: Vec<String>,
+    #[serde(skip)]
+    render_fingerprint: String,
+    /// fingerprints of the note types defined in `.alas/notetypes` by their name
+    #[serde(skip)]
+    notetype_fingerprints: HashMap<String, String>,
+    /// version of the config file if it was upgraded when loading, which is saved with the next write
+    #[serde(skip)]
+    migrated_from: Option<u64>,
+    #[serde(default)]
+    pub version: u64,
     pub anki_path: PathBuf,
     pub anki_profile: String,
     pub anki_deck_name: String,
     pub anki_identifier: String,
     pub anki_deck_id: Option<i64>,
     pub anki_notetype_id: Option<i64>,
+    #[serde(default)]
+    pub anki_cloze_notetype_id: Option<i64>,
+    /// ids of the note types defined in `.alas/notetypes`
+    #[serde(default)]
+    pub anki_notetypes: HashMap<String, i64>,
+    #[serde(default = "default_include")]
+    pub include: Vec<String>,
+    #[serde(default = "default_exclude")]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub root_document: Option<PathBuf>,
+    #[serde(default)]
+    pub subdecks: SubdeckMode,
+    /// tag notes with the names of their file, chapter and section
+    #[serde(default)]
+    pub auto_tags: bool,
+    #[serde(default)]
+    pub renderer: RendererKind,
+    #[serde(default)]
+    pub output: OutputMode,
+    #[serde(default)]
+    pub image_format: ImageFormat,
+    #[serde(default = "default_dpi")]
+    pub dpi: u32,
+    #[serde(default)]
+    pub theme_aware: bool,
+    #[serde(default)]
+    pub theme_colors: BTreeMap<String, String>,
+    /// seconds to wait for the anki collection if it is in use
+    #[serde(default = "default_busy_timeout")]
+    pub busy_timeout: u64,
+    /// number of snapshots kept in `.alas/backups`
+    #[serde(default = "default_backups")]
+    pub backups: usize,
+    #[serde(default)]
+    pub deck_options: DeckOptions,
     note_hashes: HashMap<String, String>,
     anki_notes: HashMap<String, i64>,
+    #[serde(default)]
+    note_decks: HashMap<String, i64>,
+    #[serde(default)]
+    note_tags: HashMap<String, Vec<String>>,
 }
 
 impl Default for Config {
@@ -35,6 +98,10 @@ impl Default for Config {
         Self {
             config_path: PathBuf::from(".alas/config.json"),
             synced_notes: Vec::new(),
+            render_fingerprint: String::new(),
+            notetype_fingerprints: HashMap::new(),
+            migrated_from: None,
+            version: CONFIG_VERSION,
             anki_path: dirs::data_dir()
                 .expect("failed to locate data directory")
                 .join("Anki2"),
@@ -44,12 +111,50 @@ impl Default for Config {
             anki_identifier: String::new(),
             anki_deck_id: None,
             anki_notetype_id: None,
+            anki_cloze_notetype_id: None,
+            anki_notetypes: HashMap::new(),
+            include: default_include(),
+            exclude: default_exclude(),
+            root_document: None,
+            subdecks: SubdeckMode::default(),
+            auto_tags: false,
+            renderer: RendererKind::default(),
+            output: OutputMode::default(),
+            image_format: ImageFormat::default(),
+            dpi: default_dpi(),
+            theme_aware: false,
+            theme_colors: BTreeMap::new(),
+            busy_timeout: default_busy_timeout(),
+            backups: default_backups(),
+            deck_options: DeckOptions::default(),
             note_hashes: HashMap::new(),
             anki_notes: HashMap::new(),
+            note_decks: HashMap::new(),
+            note_tags: HashMap::new(),
         }
     }
 }
 
+fn default_include() -> Vec<String> {
+    vec![String::from("**/*.tex")]
+}
+
+fn default_exclude() -> Vec<String> {
+    vec![String::from("build/**")]
+}
+
+fn default_dpi() -> u32 {
+    300
+}
+
+fn default_busy_timeout() -> u64 {
+    5
+}
+
+fn default_backups() -> usize {
+    10
+}
+
 impl Config {
     pub fn create(
         config_path: Option<PathBuf>,
@@ -80,6 +185,20 @@ impl Config {
         Ok(config)
     }
 
+    pub fn image_output(&self) -> ImageOutput {
+        ImageOutput {
+            format: self.image_format,
+            dpi: self.dpi,
+        }
+    }
+
+    /// Returns the theme applied to rendered images; only svg images can be themed.
+    pub fn svg_theme(&self) -> Option<SvgTheme> {
+        (self.theme_aware && self.image_format == ImageFormat::Svg).then(|| SvgTheme {
+            colors: self.theme_colors.clone(),
+        })
+    }
+
     pub fn load(path: Option<&PathBuf>) -> Result<Self, Error> {
         let path = match path {
             Some(p) => p.clone(),
@@ -88,43 +207,171 @@ impl Config {
 
         let data = fs::read_to_string(&path)
             .map_err(|_| Error::ConfigError("Directory is not initialized.".to_string()))?;
-        let mut config: Config = serde_json::from_str(&data)
+        let value: Value = serde_json::from_str(&data)
             .map_err(|e| Error::ConfigError(format!("Config file is corrupted: {}", e)))?;
+        let Value::Object(mut map) = value else {
+            return Err(Error::ConfigError(
+                "Config file is corrupted: expected a JSON object".to_string(),
+            ));
+        };
+
+        // files without a version field predate versioning
+        let version = map.get("version").and_then(Value::as_u64).unwrap_or(0);
+        if version > CONFIG_VERSION {
+            return Err(Error::ConfigError(format!(
+                "Config file has version {}, but this version of alas only supports up to version {}. Please upgrade alas.",
+                version, CONFIG_VERSION
+            )));
+        }
 
+        // the file is only rewritten by commands which change the project
+        if version < CONFIG_VERSION {
+            for migrate in &MIGRATIONS[version as usize..] {
+                migrate(&mut map);
+            }
+            map.insert("version".to_string(), Value::from(CONFIG_VERSION));
+        }
+
+        let mut config: Config = serde_json::from_value(Value::Object(map))
+            .map_err(|e| Error::ConfigError(format!("Config file is corrupted: {}", e)))?;
         config.config_path = path;
+        config.migrated_from = (version < CONFIG_VERSION).then_some(version);
+
         Ok(config)
     }
 
+    pub fn anki_collection_path(&self) -> PathBuf {
+        self.anki_path
+            .join(&self.anki_profile)
+            .join("collection.anki2")
+    }
+
+    pub fn alas_dir(&self) -> PathBuf {
+        self.config_path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| PathBuf::from(".alas"))
+    }
+
+    /// Replaces the config file atomically, so an interruption never leaves a partial file. A file
+    /// of an older version is kept as `config.json.v<version>.bak` before it is replaced.
     pub fn write_back(&self) -> Result<(), Error> {
+        if let Some(version) = self.migrated_from {
+            let backup_path = self
+                .config_path
+                .with_extension(format!("json.v{}.bak", version));
+            if !backup_path.exists() {
+                fs::copy(&self.config_path, &backup_path)?;
+            }
+        }
+
         let data = serde_json::to_string_pretty(self)?;
-        fs::write(&self.config_path, data)?;
+        let mut file = anki_io::new_tempfile_in_parent_of(&self.config_path)?;
+        file.write_all(data.as_bytes())?;
+        anki_io::atomic_rename(file, &self.config_path, true)?;
         Ok(())
     }
 
-    pub fn start_check_in(&mut self) {
+    pub fn start_check_in(
+        &mut self,
+        render_fingerprint: String,
+        notetype_fingerprints: HashMap<String, String>,
+    ) {
         self.synced_notes = Vec::new();
+        self.render_fingerprint = render_fingerprint;
+        self.notetype_fingerprints = notetype_fingerprints;
     }
 
     pub fn check_in_note(&mut self, note: &Note) -> NoteState {
         self.synced_notes.push(note.id.clone());
         match self.note_hashes.get(&note.id) {
-            Some(existing_hash) if existing_hash == &note.hash_text() => NoteState::Unchanged,
+            Some(existing_hash) if existing_hash == &self.note_hash(note) => NoteState::Unchanged,
             Some(_) => NoteState::Changed,
             None => NoteState::New,
         }
     }
 
-    pub fn update_note_state(&mut self, note: &Note) {
-        self.note_hashes.insert(note.id.clone(), note.hash_text());
+    /// Notes of a note type defined in the project also change with the fields of the note type.
+    pub fn note_hash(&self, note: &Note) -> String {
+        let notetype_fingerprint = match note.kind {
+            NoteKind::Basic => note
+                .options
+                .note_type
+                .as_ref()
+                .and_then(|name| self.notetype_fingerprints.get(name)),
+            NoteKind::Cloze => None,
+        };
+        match notetype_fingerprint {
+            Some(fingerprint) => {
+                note.hash_text(&format!("{}|{}", self.render_fingerprint, fingerprint))
+            }
+            None => note.hash_text(&self.render_fingerprint),
+        }
     }
 
-    pub fn store_ankinote_id(&mut self, note: &Note, ankinote_id: i64) {
-        self.anki_notes.insert(note.id.clone(), ankinote_id);
+    pub fn set_note_hash(&mut self, note_id: &str, hash: &str) {
+        self.note_hashes
+            .insert(note_id.to_string(), hash.to_string());
+    }
+
+    pub fn store_ankinote_id(&mut self, note_id: &str, ankinote_id: i64) {
+        self.anki_notes.insert(note_id.to_string(), ankinote_id);
     }
 
     pub fn remove_note(&mut self, note_id: &str) {
         self.note_hashes.remove(note_id);
         self.anki_notes.remove(note_id);
+        self.note_decks.remove(note_id);
+        self.note_tags.remove(note_id);
+    }
+
+    /// Replaces the anki notes of the project by the ones found in the collection, given with the
+    /// deck of their cards. Notes whose anki note changed are updated by the next sync, notes
+    /// missing in the collection are added again.
+    pub fn relink_notes(&mut self, ankinotes: &HashMap<String, (i64, Option<i64>)>) {
+        let stale: Vec<String> = self
+            .note_hashes
+            .keys()
+            .chain(self.anki_notes.keys())
+            .filter(|id| !ankinotes.contains_key(*id))
+            .cloned()
+            .collect();
+        for note_id in stale {
+            self.remove_note(&note_id);
+        }
+
+        for (note_id, (ankinote_id, deck_id)) in ankinotes {
+            // an empty hash never matches, so the note is rendered and updated
+            if self.get_ankinote_id(note_id) != Some(*ankinote_id) {
+                self.set_note_hash(note_id, "");
+            }
+            self.note_hashes.entry(note_id.clone()).or_default();
+            self.store_ankinote_id(note_id, *ankinote_id);
+            match deck_id {
+                Some(deck_id) => self.set_note_deck(note_id, *deck_id),
+                None => {
+                    self.note_decks.remove(note_id);
+                }
+            }
+        }
+    }
+
+    /// Returns the deck the cards of the note were last placed in; `None` means the project deck.
+    pub fn get_note_deck(&self, note_id: &str) -> Option<i64> {
+        self.note_decks.get(note_id).copied()
+    }
+
+    pub fn set_note_deck(&mut self, note_id: &str, deck_id: i64) {
+        self.note_decks.insert(note_id.to_string(), deck_id);
+    }
+
+    /// Returns the tags alas last wrote to the note; other tags of the note were added in anki.
+    pub fn get_note_tags(&self, note_id: &str) -> &[String] {
+        self.note_tags.get(note_id).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn set_note_tags(&mut self, note_id: &str, tags: &[String]) {
+        self.note_tags.insert(note_id.to_string(), tags.to_vec());
     }
 
     pub fn get_ankinote_id(&self, note_id: &str) -> Option<i64> {
@@ -139,3 +386,63 @@ impl Config {
             .collect()
     }
 }
+
+/// Version 0 files predate the version field; all keys added since then have defaults.
+fn migrate_v0_to_v1(_config: &mut Map<String, Value>) {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn load(data: &str) -> (tempfile::TempDir, Result<Config, Error>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        fs::write(&path, data).unwrap();
+        let config = Config::load(Some(&path));
+        (dir, config)
+    }
+
+    #[test]
+    fn migrations() {
+        // a config written before the version field was added
+        let v0 = r#"{
+            "anki_path": "/anki", "anki_profile": "User 1", "anki_deck_name": "Analysis",
+            "anki_identifier": "analysis", "anki_deck_id": 1, "anki_notetype_id": 2,
+            "note_hashes": {"n1": "hash"}, "anki_notes": {"n1": 3}
+        }"#;
+        let (dir, config) = load(v0);
+        let config = config.unwrap();
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.get_ankinote_id("n1"), Some(3));
+        assert_eq!(config.get_note_deck("n1"), None);
+        assert!(config.get_note_tags("n1").is_empty());
+        assert!(config.anki_notetypes.is_empty());
+
+        // the file is only replaced when the config is written
+        assert_eq!(fs::read_to_string(&config.config_path).unwrap(), v0);
+        assert!(!dir.path().join("config.json.v0.bak").exists());
+
+        config.write_back().unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("config.json.v0.bak")).unwrap(),
+            v0
+        );
+        let config = Config::load(Some(&config.config_path)).unwrap();
+        assert_eq!(config.migrated_from, None);
+        assert_eq!(config.get_ankinote_id("n1"), Some(3));
+    }
+
+    #[test]
+    fn unsupported_files() {
+        for data in ["[]", "1", "\"config\"", "null", "{"] {
+            let (_dir, config) = load(data);
+            assert!(matches!(
+                config,
+                Err(Error::ConfigError(message)) if message.starts_with("Config file is corrupted")
+            ));
+        }
+
+        let (_dir, config) = load(&format!(r#"{{"version": {}}}"#, CONFIG_VERSION + 1));
+        assert!(matches!(config, Err(Error::ConfigError(message)) if message.contains("upgrade")));
+    }
+}
@@ -0,0 +1,100 @@
+use serde::{Deserialize, Serialize};
+
+use anki_db::DeckConfigInnerProto;
+
+use crate::error::Error;
+
+/// Number of weights of the FSRS version used by anki.
+const FSRS_WEIGHT_COUNT: usize = 17;
+
+/// Options of the project deck set in the `deck_options` section of the config; options which are
+/// not set keep the value chosen in anki.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct DeckOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_per_day: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reviews_per_day: Option<u32>,
+    /// learning steps in minutes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub learn_steps: Option<Vec<f32>>,
+    /// relearning steps in minutes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relearn_steps: Option<Vec<f32>>,
+    /// retention the fsrs scheduler aims for, e.g. `0.9`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub desired_retention: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fsrs_weights: Option<Vec<f32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bury_new: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bury_reviews: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bury_interday_learning: Option<bool>,
+}
+
+impl DeckOptions {
+    /// Sets the options on the deck config of anki.
+    pub fn apply(&self, inner: &mut DeckConfigInnerProto) -> Result<(), Error> {
+        self.validate()?;
+
+        if let Some(new_per_day) = self.new_per_day {
+            inner.new_per_day = new_per_day;
+        }
+        if let Some(reviews_per_day) = self.reviews_per_day {
+            inner.reviews_per_day = reviews_per_day;
+        }
+        if let Some(learn_steps) = &self.learn_steps {
+            inner.learn_steps = learn_steps.clone();
+        }
+        if let Some(relearn_steps) = &self.relearn_steps {
+            inner.relearn_steps = relearn_steps.clone();
+        }
+        if let Some(desired_retention) = self.desired_retention {
+            inner.desired_retention = desired_retention;
+        }
+        if let Some(fsrs_weights) = &self.fsrs_weights {
+            inner.fsrs_weights = fsrs_weights.clone();
+        }
+        if let Some(bury_new) = self.bury_new {
+            inner.bury_new = bury_new;
+        }
+        if let Some(bury_reviews) = self.bury_reviews {
+            inner.bury_reviews = bury_reviews;
+        }
+        if let Some(bury_interday_learning) = self.bury_interday_learning {
+            inner.bury_interday_learning = bury_interday_learning;
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let steps = self.learn_steps.iter().chain(&self.relearn_steps).flatten();
+        if steps.into_iter().any(|step| *step <= 0.0) {
+            return Err(Error::ConfigError(
+                "Learning steps in deck_options must be positive.".to_string(),
+            ));
+        }
+        if self
+            .desired_retention
+            .is_some_and(|r| !(0.7..=0.99).contains(&r))
+        {
+            return Err(Error::ConfigError(
+                "desired_retention in deck_options must be between 0.7 and 0.99.".to_string(),
+            ));
+        }
+        if let Some(weights) = self
+            .fsrs_weights
+            .as_ref()
+            .filter(|w| !w.is_empty() && w.len() != FSRS_WEIGHT_COUNT)
+        {
+            return Err(Error::ConfigError(format!(
+                "fsrs_weights in deck_options must contain {} weights, found {}.",
+                FSRS_WEIGHT_COUNT,
+                weights.len()
+            )));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,157 @@
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+use crate::config::Config;
+use crate::error::Error;
+use crate::note;
+
+lazy_static! {
+    static ref INPUT_RE: Regex = Regex::new(r"\\(?:input|include|subfile)\s*\{([^}]+)\}").unwrap();
+}
+
+pub struct NoteFiles {
+    /// files contributing notes, in sync order
+    pub files: Vec<PathBuf>,
+    /// files containing notes which are not reachable from the root document
+    pub orphaned: Vec<PathBuf>,
+}
+
+pub fn find_note_files(config: &Config) -> Result<NoteFiles, Error> {
+    let exclude = build_glob_set(&config.exclude)?;
+
+    let root = match &config.root_document {
+        Some(root) => root.strip_prefix(".").unwrap_or(root),
+        None => {
+            return Ok(NoteFiles {
+                files: find_tex_files(config)?,
+                orphaned: Vec::new(),
+            })
+        }
+    };
+
+    if !root.exists() {
+        return Err(Error::ConfigError(format!(
+            "Root document '{}' does not exist.",
+            root.display()
+        )));
+    }
+
+    let mut files = Vec::new();
+    let mut visited = HashSet::new();
+    follow_inputs(root, &mut visited, &mut files)?;
+    files.retain(|file| !exclude.is_match(file));
+
+    // files with notes which are no longer part of the document
+    let mut orphaned = Vec::new();
+    for file in find_tex_files(config)? {
+        if !visited.contains(&file) && contains_notes(&file)? {
+            orphaned.push(file);
+        }
+    }
+
+    Ok(NoteFiles { files, orphaned })
+}
+
+fn follow_inputs(
+    file: &Path,
+    visited: &mut HashSet<PathBuf>,
+    files: &mut Vec<PathBuf>,
+) -> Result<(), Error> {
+    if !visited.insert(file.to_path_buf()) {
+        return Ok(());
+    }
+
+    files.push(file.to_path_buf());
+
+    let content = fs::read_to_string(file)?;
+    for line in content.lines() {
+        let line = strip_comment(line);
+        for cap in INPUT_RE.captures_iter(line) {
+            // paths are resolved relative to the project root like latex does
+            let mut input = PathBuf::from(cap.get(1).unwrap().as_str().trim());
+            if input.extension().is_none() {
+                input.set_extension("tex");
+            }
+            let input = input.strip_prefix(".").unwrap_or(&input).to_path_buf();
+
+            if input.is_file() {
+                follow_inputs(&input, visited, files)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '%' if !escaped => return &line[..i],
+            '\\' => escaped = !escaped,
+            _ => escaped = false,
+        }
+    }
+    line
+}
+
+fn contains_notes(file: &PathBuf) -> Result<bool, Error> {
+    Ok(!note::parse_tex_file(file)?.is_empty() || note::count_notes_without_id(file)? > 0)
+}
+
+fn find_tex_files(config: &Config) -> Result<Vec<PathBuf>, Error> {
+    let include = build_glob_set(&config.include)?;
+    let exclude = build_glob_set(&config.exclude)?;
+
+    let mut tex_files = Vec::new();
+
+    // skip hidden directories such as .alas or .git
+    let walker = WalkDir::new(".").into_iter().filter_entry(|entry| {
+        entry.depth() == 0 || !entry.file_name().to_string_lossy().starts_with('.')
+    });
+
+    for entry in walker {
+        let entry =
+            entry.map_err(|e| Error::AlasError(format!("Failed to scan directory: {}", e)))?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        // match globs against the path relative to the project root
+        let path = entry
+            .path()
+            .strip_prefix(".")
+            .unwrap_or(entry.path())
+            .to_path_buf();
+        if include.is_match(&path) && !exclude.is_match(&path) {
+            tex_files.push(path);
+        }
+    }
+
+    // deterministic sync order
+    tex_files.sort();
+    Ok(tex_files)
+}
+
+fn build_glob_set(patterns: &[String]) -> Result<GlobSet, Error> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| {
+                Error::ConfigError(format!("Invalid glob pattern '{}': {}", pattern, e))
+            })?;
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .map_err(|e| Error::ConfigError(format!("Invalid glob patterns: {}", e)))
+}
@@ -13,6 +13,8 @@ pub enum Error {
     AnkiDbError(#[from] ::anki_db::Error),
     #[error(transparent)]
     DbError(#[from] ::rusqlite::Error),
+    #[error(transparent)]
+    FileIoError(#[from] ::anki_io::FileIoError),
     #[error("{0}")]
     ConfigError(String),
     #[error("{0}")]
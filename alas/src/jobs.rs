@@ -10,7 +10,7 @@ use crate::note::Note;
 
 #[derive(Clone)] // TODO no clone necessary
 pub enum AnkiJob {
-    Modify(Note, ModifyAction, JobState), // TODO make Note read only -> Rc
+    Modify(Box<Note>, ModifyAction, JobState), // TODO make Note read only -> Rc
     Delete(String, JobState),
 }
 
@@ -0,0 +1,140 @@
+use rusqlite::Transaction;
+use serde::{Deserialize, Serialize};
+use std::{fs, io::Write, path::PathBuf};
+
+use anki_db::Note as AnkiNote;
+
+use crate::config::Config;
+use crate::error::Error;
+
+/// A database write whose effect on the config is not yet saved.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum JournalEntry {
+    Add {
+        note_id: String,
+        ankinote_id: i64,
+        hash: String,
+        #[serde(default)]
+        deck_id: Option<i64>,
+        #[serde(default)]
+        tags: Vec<String>,
+    },
+    Update {
+        note_id: String,
+        ankinote_id: i64,
+        fields: Vec<String>,
+        hash: String,
+        /// modification time of the updated note, which changes with every update
+        #[serde(default)]
+        mtime: Option<i64>,
+    },
+    Delete {
+        note_id: String,
+        ankinote_id: i64,
+    },
+}
+
+impl JournalEntry {
+    /// Applies the write to the config once it is committed to the database.
+    pub fn apply(&self, config: &mut Config) {
+        match self {
+            JournalEntry::Add {
+                note_id,
+                ankinote_id,
+                hash,
+                deck_id,
+                tags,
+            } => {
+                config.store_ankinote_id(note_id, *ankinote_id);
+                config.set_note_hash(note_id, hash);
+                if let Some(deck_id) = deck_id {
+                    config.set_note_deck(note_id, *deck_id);
+                }
+                config.set_note_tags(note_id, tags);
+            }
+            JournalEntry::Update { note_id, hash, .. } => config.set_note_hash(note_id, hash),
+            JournalEntry::Delete { note_id, .. } => config.remove_note(note_id),
+        }
+    }
+
+    /// Checks whether the write reached the database before the sync was interrupted.
+    fn is_committed(&self, trans: &Transaction) -> Result<bool, Error> {
+        Ok(match self {
+            JournalEntry::Add { ankinote_id, .. } => {
+                AnkiNote::load_without_cards(trans, *ankinote_id)?.is_some()
+            }
+            // image fields look the same before and after an update
+            JournalEntry::Update {
+                ankinote_id,
+                fields,
+                mtime,
+                ..
+            } => AnkiNote::load_without_cards(trans, *ankinote_id)?.is_some_and(|note| {
+                note.get_fields() == fields.as_slice()
+                    && mtime.is_none_or(|mtime| note.get_mtime() == mtime)
+            }),
+            JournalEntry::Delete { ankinote_id, .. } => {
+                AnkiNote::load_without_cards(trans, *ankinote_id)?.is_none()
+            }
+        })
+    }
+}
+
+/// Records database writes in `.alas/journal.json` before they are committed, so that the config
+/// can be brought in line with the database after an interruption.
+pub struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            path: config.alas_dir().join("journal.json"),
+        }
+    }
+
+    pub fn record(&self, entries: &[JournalEntry]) -> Result<(), Error> {
+        let data = serde_json::to_string_pretty(entries)?;
+        let mut file = anki_io::new_tempfile_in_parent_of(&self.path)?;
+        file.write_all(data.as_bytes())?;
+        anki_io::atomic_rename(file, &self.path, true)?;
+        Ok(())
+    }
+
+    pub fn clear(&self) -> Result<(), Error> {
+        if self.path.exists() {
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+
+    /// Applies the committed writes of an interrupted sync to the config and returns their number.
+    pub fn reconcile(&self, trans: &Transaction, config: &mut Config) -> Result<usize, Error> {
+        if !self.path.exists() {
+            return Ok(0);
+        }
+
+        let data = fs::read_to_string(&self.path)?;
+        let entries: Vec<JournalEntry> = serde_json::from_str(&data)
+            .map_err(|e| Error::ConfigError(format!("Journal file is corrupted: {}", e)))?;
+
+        let mut num_committed = 0;
+        for entry in &entries {
+            if entry.is_committed(trans)? {
+                entry.apply(config);
+                num_committed += 1;
+
+                // the images of the note may not have been moved into place, so it is rendered again
+                if let JournalEntry::Add { note_id, .. } | JournalEntry::Update { note_id, .. } =
+                    entry
+                {
+                    config.set_note_hash(note_id, "");
+                }
+            }
+        }
+
+        config.write_back()?;
+        self.clear()?;
+        Ok(num_committed)
+    }
+}
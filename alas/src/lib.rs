@@ -1,22 +1,44 @@
 #![feature(mpmc_channel)]
 
+use colored::Colorize;
 use convert_case::{Case, Casing};
 use include_dir::{include_dir, Dir};
-use rusqlite::{self, Connection, Transaction};
-use std::{env, fs, path::PathBuf, process::Command};
+use rusqlite::{self, Connection, ErrorCode, Transaction};
+use std::collections::{HashMap, HashSet};
+use std::{env, fs, path::PathBuf, sync::mpmc, thread, time::Duration};
 use unicase::UniCase;
 
 use anki_db::{self, AnkiDeck, Note as AnkiNote, Notetype as AnkiNotetype};
 
+use backup::SyncSnapshot;
 use config::{Config, NoteState};
+use deckoptions::DeckOptions;
 use jobs::{AnkiJob, JobMonitor, JobState, ModifyAction};
-use note::Note;
+use journal::{Journal, JournalEntry};
+use lock::ProjectLock;
+use messages::{print_message, MessageType};
+use note::{Note, NoteKind};
+use notetype::{Notetypes, ProjectNotetype};
+use render::{RenderContext, RenderEvent};
+use subdeck::Outline;
 
 pub use error::Error;
 
+mod backup;
 mod config;
+mod deckoptions;
+mod discovery;
 mod jobs;
+mod journal;
+mod lock;
+mod mathjax;
 mod note;
+mod notetype;
+mod relink;
+mod render;
+mod renderer;
+mod subdeck;
+mod theme;
 
 pub mod error;
 pub mod messages;
@@ -26,6 +48,7 @@ pub fn init_dir(
     deck_name: Option<String>,
     identifier: Option<String>,
     files: bool,
+    adopt: bool,
 ) -> Result<(), Error> {
     let deck_name = deck_name.unwrap_or_else(|| {
         get_current_dir()
@@ -33,26 +56,15 @@ pub fn init_dir(
             .to_case(Case::Title)
     });
 
-    let identifier = identifier.unwrap_or_else(|| deck_name.to_case(Case::Kebab)); // TODO non injective mapping from deck name to identifier
+    // different deck names can map to the same identifier, collisions are detected below
+    let identifier = identifier.unwrap_or_else(|| deck_name.to_case(Case::Kebab));
 
     let mut config = Config::create(None, deck_name, identifier, anki_profile)?;
 
-    if files {
-        init_project_dir()?;
-    }
+    // held until the config is written, so a concurrent init fails instead of overwriting it
+    let _lock = ProjectLock::acquire(&config.alas_dir())?;
 
-    let anki_db_path = config
-        .anki_path
-        .join(&config.anki_profile)
-        .join("collection.anki2");
-
-    let mut conn = rusqlite::Connection::open(anki_db_path)
-        .map_err(|_| Error::AlasError("Failed to open an anki database connection.".to_string()))?;
-
-    conn.create_collation("unicase", |lhs: &str, rhs: &str| {
-        UniCase::new(lhs).cmp(&UniCase::new(rhs))
-    })
-    .expect("can register unicase");
+    let mut conn = open_collection(&config)?;
 
     // check db scheme compatibility
     if !anki_db::check_db_compatibility(&mut conn)? {
@@ -63,372 +75,1147 @@ pub fn init_dir(
 
     let trans = new_transaction(&mut conn)?;
 
-    let mut deck = AnkiDeck::new(&config.anki_deck_name);
-    config.anki_deck_id = Some(deck.write_to_db(&trans)?);
-
-    let mut notetype = AnkiNotetype::new(&format!("{}-notetype", &config.anki_identifier))
-        .with_field("front")
-        .with_field("back")
-        .with_template(
-            &format!("{}-template", &config.anki_identifier),
-            include_str!("../templates/anki/minimal_front.txt"),
-            include_str!("../templates/anki/minimal_back.txt"),
-            config.anki_deck_id.expect("deck is written to db"),
-        );
+    let mut deck = match AnkiDeck::load_by_name(&config.anki_deck_name, &trans)? {
+        Some(deck) if adopt => {
+            print_message(
+                MessageType::Info,
+                &format!("Adopted the existing deck '{}'.", deck.name),
+            );
+            deck
+        }
+        Some(deck) => {
+            return Err(Error::AlasError(format!(
+                "Deck '{}' already exists in Anki. Use --adopt to reuse it or choose another name with --deck.",
+                deck.name
+            )))
+        }
+        None => AnkiDeck::new(&config.anki_deck_name),
+    };
+    update_deck_options(&mut deck, &config.deck_options, &trans)?;
+    config.anki_deck_id = Some(match deck.get_id() {
+        Some(id) => id,
+        None => deck.write_to_db(&trans)?,
+    });
+
+    let mut desired = notetype::basic_notetype(&config, true)?;
+    config.anki_notetype_id = Some(match adopt_notetype(&desired, adopt, &trans)? {
+        Some(mut notetype) => {
+            notetype::update_notetype(&mut notetype, &desired, &trans)?;
+            notetype.get_id().expect("notetype is loaded from db")
+        }
+        None => desired.write_to_db(&trans)?,
+    });
 
-    config.anki_notetype_id = Some(notetype.write_to_db(&trans)?);
+    // the other notetypes are created on the first sync which needs them
+    let cloze = adopt_notetype(&notetype::cloze_notetype(&config)?, adopt, &trans)?;
+    config.anki_cloze_notetype_id = cloze.and_then(|notetype| notetype.get_id());
+    for project_notetype in ProjectNotetype::load_all(&config.alas_dir())? {
+        let desired = project_notetype.build(&config);
+        if let Some(notetype) = adopt_notetype(&desired, adopt, &trans)? {
+            config.anki_notetypes.insert(
+                project_notetype.name,
+                notetype.get_id().expect("notetype is loaded from db"),
+            );
+        }
+    }
 
     trans.commit()?;
     config.write_back()?;
+    backup::write_gitignore(&config)?;
+
+    if files {
+        init_project_dir()?;
+    }
     Ok(())
 }
 
+/// Returns the notetype with the name of `desired` if it exists and may be adopted; notetypes of
+/// another kind or with other fields belong to someone else.
+fn adopt_notetype(
+    desired: &AnkiNotetype,
+    adopt: bool,
+    trans: &Transaction,
+) -> Result<Option<AnkiNotetype>, Error> {
+    let Some(existing) = AnkiNotetype::load_by_name(desired.get_name(), trans)? else {
+        return Ok(None);
+    };
+
+    if existing.is_cloze() != desired.is_cloze() || existing.field_names() != desired.field_names()
+    {
+        return Err(Error::AlasError(format!(
+            "Note type '{}' already exists in Anki, but was not created by alas. Choose another identifier with --identifier.",
+            existing.get_name()
+        )));
+    }
+    if !adopt {
+        return Err(Error::AlasError(format!(
+            "Note type '{}' already exists in Anki. Use --adopt to reuse it or choose another identifier with --identifier.",
+            existing.get_name()
+        )));
+    }
+
+    print_message(
+        MessageType::Info,
+        &format!("Adopted the existing note type '{}'.", existing.get_name()),
+    );
+    Ok(Some(existing))
+}
+
 pub fn get_current_dir() -> Option<String> {
     Some(env::current_dir().ok()?.file_name()?.to_str()?.to_string())
 }
 
-pub fn sync_notes(batch_size: usize) -> Result<(), Error> {
+pub fn required_programs() -> Result<Vec<&'static str>, Error> {
+    let config = Config::load(None)?;
+    Ok(config
+        .renderer
+        .build(config.image_output())
+        .required_programs())
+}
+
+pub fn sync_notes(
+    batch_size: usize,
+    num_workers: Option<usize>,
+    atomic: bool,
+) -> Result<(), Error> {
     let mut config = Config::load(None)?;
 
-    let tex_files = find_tex_files_in_current_dir()?;
+    // held until the sync is finished
+    let _lock = ProjectLock::acquire(&config.alas_dir())?;
+
+    let note_files = discovery::find_note_files(&config)?;
 
-    let anki_db_path = config
-        .anki_path
-        .join(&config.anki_profile)
-        .join("collection.anki2");
     let anki_media_dir = config
         .anki_path
         .join(&config.anki_profile)
         .join("collection.media");
 
-    // TODO function for connection and transaction
-    let mut conn = rusqlite::Connection::open(anki_db_path)
-        .map_err(|_| Error::AlasError("Failed to open an anki database connection.".to_string()))?;
-
-    conn.create_collation("unicase", |lhs: &str, rhs: &str| {
-        UniCase::new(lhs).cmp(&UniCase::new(rhs))
-    })
-    .expect("fail register unicase");
-
-    let trans = new_transaction(&mut conn)?;
+    let conn = open_collection(&config)?;
+
+    // the snapshot is only saved once the first change is about to be committed
+    let mut snapshot = SyncSnapshot::new(&conn, &config)?;
+
+    // the writes before the first batch of notes are committed together with that batch
+    let mut pending = None;
+    let trans = next_transaction(&mut pending, &conn)?;
+
+    // finish the bookkeeping of an interrupted sync
+    let journal = Journal::new(&config);
+    let num_recovered = journal.reconcile(&trans, &mut config)?;
+    if num_recovered > 0 {
+        print_message(
+            MessageType::Info,
+            &format!(
+                "Recovered {} changes of an interrupted sync.",
+                num_recovered
+            ),
+        );
+    }
 
-    let deck = AnkiDeck::load(config.anki_deck_id.expect("deck is written to db"), &trans)?
+    let mut deck = AnkiDeck::load(config.anki_deck_id.expect("deck is written to db"), &trans)?
         .ok_or_else(|| Error::AlasError("Anki deck could not be found.".to_string()))?;
 
-    // TODO do not load notetype from db, just use id from config
+    // the options are shared with the subdecks
+    if update_deck_options(&mut deck, &config.deck_options, &trans)? {
+        print_message(MessageType::Info, "Updated the options of the deck.");
+    }
+
     let notetype_id = config.anki_notetype_id.expect("notetype is written to db");
-    let notetype = AnkiNotetype::load(notetype_id, &trans)?
+    let mut notetype = AnkiNotetype::load(notetype_id, &trans)?
         .ok_or_else(|| Error::AlasError("Anki note could not be found.".to_string()))?;
 
-    let notes = tex_files
+    let mut outline = Outline::default();
+    let mut notes = note_files
+        .files
         .iter()
         .map(|file| {
             note::insert_id_if_missing(file)?;
-            Ok(note::parse_tex_file(file))
+            let mut notes = note::parse_tex_file(file)?;
+            outline.assign_subdecks(config.subdecks, file, &mut notes)?;
+            if config.auto_tags {
+                notes.iter_mut().for_each(Note::add_structure_tags);
+            }
+            Ok(notes)
         })
         .collect::<Result<Vec<_>, Error>>()?
         .into_iter()
-        .flatten();
+        .flatten()
+        .collect::<Vec<_>>();
 
-    trans.commit()?;
+    let user_macros = mathjax::user_macros(&render::read_preamble());
+
+    for n in notes.iter_mut() {
+        n.resolve_outputs(config.output, &user_macros);
+    }
+
+    // changes of the shipped templates and of the styling reach existing notetypes; notetypes of
+    // projects initialized before reverse cards get the template once it is needed
+    let needs_reverse = notes.iter().any(|n| {
+        n.kind == NoteKind::Basic
+            && n.options
+                .cards
+                .unwrap_or_default()
+                .template_idxs()
+                .contains(&1)
+    });
+    let reverse = needs_reverse || notetype.has_template(&notetype::reverse_template_name(&config));
+    notetype::update_notetype(
+        &mut notetype,
+        &notetype::basic_notetype(&config, reverse)?,
+        &trans,
+    )?;
+
+    let project_notetypes = sync_project_notetypes(&mut config, &trans)?;
+    let mut notetypes = Notetypes {
+        basic: notetype,
+        cloze: None,
+        project: project_notetypes,
+    };
+
+    // the cloze notetype is only created once a project uses clozes
+    if notes.iter().any(|n| n.kind == NoteKind::Cloze) {
+        notetypes.cloze = Some(get_or_create_cloze_notetype(&mut config, &trans)?);
+    }
+
+    let mut decks = HashMap::new();
+    for n in &notes {
+        if !decks.contains_key(&n.subdeck) {
+            decks.insert(
+                n.subdeck.clone(),
+                get_or_create_deck(&deck, &n.subdeck, &trans)?,
+            );
+        }
+    }
+
+    // cards of notes which moved to another part of the document follow them with their scheduling,
+    // tags added in anki are kept
+    let mut num_moved = 0;
+    let mut num_retagged = 0;
+    let mut removed_tags = Vec::new();
+    for n in &notes {
+        let Some(ankinote_id) = config.get_ankinote_id(&n.id) else {
+            continue;
+        };
+        let deck_id = decks[&n.subdeck].get_id().expect("deck is written to db");
+        let moved = config.get_note_deck(&n.id).or(deck.get_id()) != Some(deck_id);
+        let retagged = config.get_note_tags(&n.id) != n.tags.as_slice();
+        if !moved && !retagged {
+            continue;
+        }
+
+        if let Some(ankinote) = AnkiNote::load_without_cards(&trans, ankinote_id)? {
+            if moved {
+                ankinote.move_cards(deck_id, &trans)?;
+                num_moved += 1;
+            }
+            if retagged {
+                let previous = config.get_note_tags(&n.id);
+                removed_tags.extend(previous.iter().filter(|t| !n.tags.contains(t)).cloned());
+                let mut tags: Vec<String> = ankinote
+                    .get_tags()
+                    .iter()
+                    .filter(|t| !previous.contains(t))
+                    .chain(&n.tags)
+                    .cloned()
+                    .collect();
+                tags.sort();
+                tags.dedup();
+                ankinote.with_tags(tags).write_to_db(&trans)?;
+                num_retagged += 1;
+            }
+        }
+        config.set_note_deck(&n.id, deck_id);
+        config.set_note_tags(&n.id, &n.tags);
+    }
+    anki_db::remove_unused_tags(&trans, &removed_tags)?;
+    pending = Some(trans);
+
+    if num_moved > 0 {
+        print_message(
+            MessageType::Info,
+            &format!("Moved the cards of {} notes to another deck.", num_moved),
+        );
+    }
+    if num_retagged > 0 {
+        print_message(
+            MessageType::Info,
+            &format!("Updated the tags of {} notes.", num_retagged),
+        );
+    }
+
+    let num_workers = num_workers
+        .or_else(|| thread::available_parallelism().ok().map(|n| n.get()))
+        .unwrap_or(1)
+        .max(1);
+
+    let render_context = RenderContext {
+        renderer: config.renderer.build(config.image_output()),
+        image_format: config.image_format,
+        svg_theme: config.svg_theme(),
+        anki_media_dir,
+        identifier: config.anki_identifier.clone(),
+        log_dir: config.alas_dir().join("logs"),
+        project_root: env::current_dir()?,
+    };
+
+    // logs are only kept for the failures of the latest run
+    if render_context.log_dir.exists() {
+        fs::remove_dir_all(&render_context.log_dir)?;
+    }
+    render::remove_staged_media(&render_context)?;
 
     let mut monitor = JobMonitor::new(3);
 
-    config.start_check_in(); // TODO change module name: config -> ??? (logging, tracking, ...)
+    config.start_check_in(
+        render::fingerprint(&config)?,
+        notetype::fingerprints(&config.alas_dir())?,
+    ); // TODO change module name: config -> ??? (logging, tracking, ...)
 
     // TODO own type for modify jobs?
-    let mut modify_jobs = notes
+    let modify_jobs: Vec<AnkiJob> = notes
+        .into_iter()
         .filter_map(|n| match config.check_in_note(&n) {
-            NoteState::New => Some(AnkiJob::Modify(n, ModifyAction::Add, JobState::Detected)),
-            NoteState::Changed => {
-                Some(AnkiJob::Modify(n, ModifyAction::Update, JobState::Detected))
-            }
+            NoteState::New => Some(AnkiJob::Modify(
+                Box::new(n),
+                ModifyAction::Add,
+                JobState::Detected,
+            )),
+            NoteState::Changed => Some(AnkiJob::Modify(
+                Box::new(n),
+                ModifyAction::Update,
+                JobState::Detected,
+            )),
             NoteState::Unchanged => None,
         })
         .collect();
 
-    let mut delete_jobs = config
+    let mut diagnostics = Vec::new();
+
+    // notes which cannot become anki notes fail without being rendered, like notes with latex errors
+    let (mut invalid_jobs, mut modify_jobs): (Vec<_>, Vec<_>) = modify_jobs
+        .into_iter()
+        .partition(|job| matches!(job, AnkiJob::Modify(note, _, _) if note.check().is_err()));
+    for job in invalid_jobs.iter_mut() {
+        if let AnkiJob::Modify(note, _, _) = job {
+            diagnostics.extend(note.check().err());
+        }
+        job.change_state(JobState::Failed("invalid note".to_string()));
+    }
+
+    // new cards are positioned in document order after the new cards already in the collection
+    if !modify_jobs.is_empty() {
+        let trans = next_transaction(&mut pending, &conn)?;
+        let first = anki_db::reserve_new_positions(&trans, modify_jobs.len() as u32)?;
+        for (i, job) in modify_jobs.iter_mut().enumerate() {
+            if let AnkiJob::Modify(note, _, _) = job {
+                note.position = first + i as u32;
+            }
+        }
+        pending = Some(trans);
+    }
+
+    let mut delete_jobs: Vec<AnkiJob> = config
         .get_unsynced_note_ids()
         .into_iter()
         .map(|nid| AnkiJob::Delete(nid, JobState::Detected))
         .collect();
 
+    monitor.update(&invalid_jobs);
     monitor.update(&modify_jobs);
     monitor.update(&delete_jobs);
 
-    for job_chunk in modify_jobs.chunks_mut(batch_size) {
-        let mut notes = Vec::new();
+    thread::scope(|scope| -> Result<(), Error> {
+        let (task_sender, task_receiver) = mpmc::channel();
+        let (event_sender, event_receiver) = mpmc::channel();
 
-        for job in job_chunk.iter_mut() {
-            job.change_state(JobState::Processing);
+        for _ in 0..num_workers {
+            let task_receiver = task_receiver.clone();
+            let event_sender = event_sender.clone();
+            let render_context = &render_context;
+            scope.spawn(move || render::run_worker(task_receiver, event_sender, render_context));
         }
+        drop(task_receiver);
+        drop(event_sender);
+
+        for (batch_idx, job_chunk) in modify_jobs.chunks(batch_size).enumerate() {
+            let notes = job_chunk
+                .iter()
+                .map(|job| match job {
+                    AnkiJob::Modify(note, _, _) => note.as_ref().clone(),
+                    _ => panic!("only modify jobs in list"),
+                })
+                .collect();
+            task_sender
+                .send((batch_idx, notes))
+                .expect("workers are running");
+        }
+        drop(task_sender);
+
+        // batches finish in any order; database writes happen on this thread only
+        for event in event_receiver.iter() {
+            let (batch_idx, result) = match event {
+                RenderEvent::Started(batch_idx) => {
+                    let job_chunk = batch_jobs(&mut modify_jobs, batch_idx, batch_size);
+                    for job in job_chunk.iter_mut() {
+                        job.change_state(JobState::Processing);
+                    }
+                    monitor.update(&job_chunk.to_vec());
+                    continue;
+                }
+                RenderEvent::Finished(batch_idx, result) => (batch_idx, result),
+            };
 
-        for job in job_chunk.iter() {
-            // TODO: all jobs are modify jobs
-            if let AnkiJob::Modify(note, _, _) = job {
-                notes.push(note);
+            let job_chunk = batch_jobs(&mut modify_jobs, batch_idx, batch_size);
+
+            // notes which failed rendering are not written to the db
+            for (job, result) in job_chunk.iter_mut().zip(result?) {
+                if let Err(failure) = result {
+                    job.change_state(JobState::Failed(failure.message));
+                    diagnostics.extend(failure.diagnostics);
+                }
             }
+
+            // in atomic mode all notes are written together after rendering
+            if !atomic {
+                write_jobs(
+                    job_chunk.iter_mut(),
+                    &notetypes,
+                    &decks,
+                    next_transaction(&mut pending, &conn)?,
+                    &mut config,
+                    &journal,
+                    &mut snapshot,
+                )?;
+                finish_media(job_chunk.iter(), &render_context)?;
+            }
+
+            monitor.update(&job_chunk.to_vec()); // TODO update after each job?
         }
 
-        monitor.update(&job_chunk.to_vec());
+        Ok(())
+    })?;
 
-        // render batch; if one job fails all batch jobs fail
-        let result = generate_tmp_svg_files_for_batch(&notes).and_then(|_| {
-            move_tmp_svg_files_to_anki_media(&notes, &anki_media_dir, &config.anki_identifier)
-        });
-        match result {
-            Err(Error::JobError(msg)) => {
-                for job in job_chunk.iter_mut() {
-                    job.change_state(JobState::Failed(msg.clone()));
-                }
-                monitor.update(&job_chunk.to_vec());
+    for job in delete_jobs.iter_mut() {
+        job.change_state(JobState::Processing);
+    }
+
+    if atomic {
+        write_jobs(
+            modify_jobs.iter_mut().chain(delete_jobs.iter_mut()),
+            &notetypes,
+            &decks,
+            next_transaction(&mut pending, &conn)?,
+            &mut config,
+            &journal,
+            &mut snapshot,
+        )?;
+        finish_media(modify_jobs.iter(), &render_context)?;
+        monitor.update(&modify_jobs);
+    } else {
+        write_jobs(
+            delete_jobs.iter_mut(),
+            &notetypes,
+            &decks,
+            next_transaction(&mut pending, &conn)?,
+            &mut config,
+            &journal,
+            &mut snapshot,
+        )?;
+    }
+
+    snapshot.finish(&conn, &config)?;
+
+    monitor.update(&delete_jobs);
+    monitor.close();
+
+    for diagnostic in diagnostics {
+        print_message(MessageType::Error, &diagnostic);
+    }
+
+    warn_orphaned_files(&note_files.orphaned);
+
+    Ok(())
+}
+
+pub fn restore(snapshot: Option<String>) -> Result<(), Error> {
+    let config = Config::load(None)?;
+    let _lock = ProjectLock::acquire(&config.alas_dir())?;
+    let conn = open_collection(&config)?;
+
+    let (restored, backup) = backup::restore_backup(conn, &config, snapshot.as_deref())?;
+
+    // the journal refers to the collection before restoring
+    Journal::new(&config).clear()?;
+
+    print_message(
+        MessageType::Info,
+        &format!(
+            "Restored snapshot {}. The previous state was saved as snapshot {}.",
+            restored, backup
+        ),
+    );
+    Ok(())
+}
+
+pub fn list_snapshots() -> Result<(), Error> {
+    let config = Config::load(None)?;
+    for snapshot in backup::list_snapshots(&config)? {
+        println!("{}", snapshot);
+    }
+    Ok(())
+}
+
+/// Finds the deck, the notetypes and the notes of the project in the collection by their names and
+/// note IDs and rebuilds the ids stored in the config, e.g. on another computer.
+pub fn relink() -> Result<(), Error> {
+    let mut config = Config::load(None)?;
+    let _lock = ProjectLock::acquire(&config.alas_dir())?;
+    let mut conn = open_collection(&config)?;
+
+    if config.backups > 0 {
+        backup::create_backup(&conn, &config)?;
+    }
+
+    let trans = new_transaction(&mut conn)?;
+
+    let deck = AnkiDeck::load_by_name(&config.anki_deck_name, &trans)?.ok_or_else(|| {
+        Error::AlasError(format!(
+            "Deck '{}' could not be found in Anki.",
+            config.anki_deck_name
+        ))
+    })?;
+    config.anki_deck_id = deck.get_id();
+
+    let basic_name = notetype::basic_notetype(&config, false)?
+        .get_name()
+        .to_string();
+    let basic = AnkiNotetype::load_by_name(&basic_name, &trans)?.ok_or_else(|| {
+        Error::AlasError(format!(
+            "Note type '{}' could not be found in Anki.",
+            basic_name
+        ))
+    })?;
+    config.anki_notetype_id = basic.get_id();
+
+    // the cloze and project notetypes are created again by the next sync if they are missing
+    let cloze_name = notetype::cloze_notetype(&config)?.get_name().to_string();
+    config.anki_cloze_notetype_id =
+        AnkiNotetype::load_by_name(&cloze_name, &trans)?.and_then(|nt| nt.get_id());
+
+    config.anki_notetypes.clear();
+    for project_notetype in ProjectNotetype::load_all(&config.alas_dir())? {
+        let name = project_notetype.build(&config).get_name().to_string();
+        if let Some(id) = AnkiNotetype::load_by_name(&name, &trans)?.and_then(|nt| nt.get_id()) {
+            config.anki_notetypes.insert(project_notetype.name, id);
+        }
+    }
+
+    let notetype_ids = config
+        .anki_notetype_id
+        .into_iter()
+        .chain(config.anki_cloze_notetype_id)
+        .chain(config.anki_notetypes.values().copied())
+        .collect::<Vec<_>>();
+
+    let keys = relink::NoteKeys::new(&config);
+    let mut ankinotes = HashMap::new();
+    let mut num_unlinked = 0;
+    let mut num_duplicates = 0;
+    for ntid in notetype_ids {
+        for nid in AnkiNote::ids_of_notetype(ntid, &trans)? {
+            let Some(ankinote) = AnkiNote::load_without_cards(&trans, nid)? else {
                 continue;
+            };
+            match keys.note_id(&ankinote) {
+                // the oldest note keeps the ID, copies made in anki are left alone
+                Some(note_id) if ankinotes.contains_key(&note_id) => num_duplicates += 1,
+                Some(note_id) => {
+                    ankinotes.insert(note_id, (nid, ankinote.deck_id(&trans)?));
+                }
+                None => num_unlinked += 1,
             }
-            r => r?,
         }
+    }
 
-        // write notes to anki db
-        for job in job_chunk.iter_mut() {
-            let result = match job {
-                AnkiJob::Modify(note, ModifyAction::Add, JobState::Processing) => {
-                    add_note_to_anki(note, &notetype, &deck, &mut conn, &mut config)
-                }
-                AnkiJob::Modify(note, ModifyAction::Update, JobState::Processing) => {
-                    update_note_in_anki(note, &notetype, &deck, &mut conn, &mut config)
-                }
-                _ => panic!("only modify jobs in list"),
-            };
+    let note_ids = discovery::find_note_files(&config)?
+        .files
+        .iter()
+        .map(note::parse_tex_file)
+        .collect::<Result<Vec<_>, Error>>()?
+        .into_iter()
+        .flatten()
+        .map(|n| n.id)
+        .collect::<HashSet<_>>();
+    let num_missing = note_ids
+        .iter()
+        .filter(|id| !ankinotes.contains_key(*id))
+        .count();
+    let num_removed = ankinotes
+        .keys()
+        .filter(|id| !note_ids.contains(*id))
+        .count();
 
-            match result {
-                Ok(_) => {
-                    job.change_state(JobState::Success);
+    config.relink_notes(&ankinotes);
+
+    // the journal refers to the ids of the previous collection
+    Journal::new(&config).clear()?;
+    config.write_back()?;
+
+    print_message(
+        MessageType::Info,
+        &format!(
+            "Linked {} notes in the deck '{}'.",
+            ankinotes.len(),
+            config.anki_deck_name
+        ),
+    );
+    if num_missing > 0 {
+        print_message(
+            MessageType::Info,
+            &format!(
+                "{} notes are not in Anki and will be added by the next sync.",
+                num_missing
+            ),
+        );
+    }
+    if num_removed > 0 {
+        print_message(
+            MessageType::Info,
+            &format!(
+                "{} notes are no longer in the project and will be deleted by the next sync.",
+                num_removed
+            ),
+        );
+    }
+    if num_unlinked > 0 {
+        print_message(
+            MessageType::Warning,
+            &format!(
+                "{} notes of the note types of the project have no note ID and are left alone.",
+                num_unlinked
+            ),
+        );
+    }
+    if num_duplicates > 0 {
+        print_message(
+            MessageType::Warning,
+            &format!(
+                "{} notes share their note ID with an older note and are left alone.",
+                num_duplicates
+            ),
+        );
+    }
+    Ok(())
+}
+
+fn batch_jobs(jobs: &mut [AnkiJob], batch_idx: usize, batch_size: usize) -> &mut [AnkiJob] {
+    let start = batch_idx * batch_size;
+    let end = usize::min(start + batch_size, jobs.len());
+    &mut jobs[start..end]
+}
+
+pub fn print_status() -> Result<bool, Error> {
+    let mut config = Config::load(None)?;
+
+    // the notes and the config are only consistent while no sync is running
+    let _lock = ProjectLock::acquire(&config.alas_dir())?;
+
+    let note_files = discovery::find_note_files(&config)?;
+
+    config.start_check_in(
+        render::fingerprint(&config)?,
+        notetype::fingerprints(&config.alas_dir())?,
+    );
+
+    let mut num_added = 0;
+    let mut num_updated = 0;
+    let mut problems = Vec::new();
+
+    // read-only pass: ids are not injected, so notes without an id can only be counted
+    for file in &note_files.files {
+        for _ in 0..note::count_notes_without_id(file)? {
+            println!(
+                "{}",
+                format!("+ {:<8} {}", "(no id)", file.display()).bright_green()
+            );
+            num_added += 1;
+        }
+
+        for n in note::parse_tex_file(file)? {
+            problems.extend(n.check().err());
+            match config.check_in_note(&n) {
+                NoteState::New => {
+                    println!(
+                        "{}",
+                        format!("+ {:<8} {}", n.id, n.file.display()).bright_green()
+                    );
+                    num_added += 1;
                 }
-                Err(Error::JobError(msg)) => {
-                    job.change_state(JobState::Failed(msg));
+                NoteState::Changed => {
+                    println!(
+                        "{}",
+                        format!("~ {:<8} {}", n.id, n.file.display()).bright_blue()
+                    );
+                    num_updated += 1;
                 }
-                Err(err) => Err(err)?,
+                NoteState::Unchanged => (),
             }
         }
+    }
 
-        monitor.update(&job_chunk.to_vec()); // TODO update after each job?
+    let deleted_ids = config.get_unsynced_note_ids();
+    for nid in &deleted_ids {
+        println!("{}", format!("- {}", nid).bright_red());
     }
 
-    // process delete jobs
-    for job in delete_jobs.iter_mut() {
-        job.change_state(JobState::Processing);
+    let pending = num_added + num_updated + deleted_ids.len() > 0;
+    if pending {
+        print_message(
+            MessageType::Info,
+            &format!(
+                "{} to add, {} to update, {} to delete.",
+                num_added,
+                num_updated,
+                deleted_ids.len()
+            ),
+        );
+    } else {
+        print_message(MessageType::Info, "Everything is up to date.");
+    }
+
+    // these notes will fail to sync
+    for problem in problems {
+        print_message(MessageType::Error, &problem);
+    }
+
+    warn_orphaned_files(&note_files.orphaned);
+
+    Ok(pending)
+}
+
+fn warn_orphaned_files(files: &[PathBuf]) {
+    for file in files {
+        print_message(
+            MessageType::Warning,
+            &format!(
+                "'{}' contains notes but is not part of the root document.",
+                file.display()
+            ),
+        );
+    }
+}
 
-        let result = match job {
+/// Returns the content of each anki field: an image or html shown with MathJax.
+fn field_entries(note: &Note, config: &Config) -> Vec<String> {
+    let class = note.options.note_type.as_deref().unwrap_or("default");
+
+    let src = |page: &str| {
+        format!(
+            "alas-{}-{}-{}.{}",
+            config.anki_identifier,
+            note.id,
+            page,
+            config.image_format.extension()
+        )
+    };
+
+    // themed images come with a night variant; the notetype css shows one of them
+    let img = |page: &str| match config.svg_theme() {
+        Some(_) => format!(
+            "<img class=\"{0} alas-day\" src=\"{1}\"><img class=\"{0} alas-night\" src=\"{2}\">",
+            class,
+            src(page),
+            src(&theme::night_page(page))
+        ),
+        None => format!("<img class=\"{}\" src=\"{}\">", class, src(page)),
+    };
+
+    (0..note.fields.len())
+        .map(|i| match &note.html_fields[i] {
+            Some(html) => format!("<div class=\"{}\">{}</div>", class, html),
+            // the question image is given as the hint, which anki shows in place of the cloze
+            None if note.kind == NoteKind::Cloze && i == 0 => note
+                .cloze_ordinals()
+                .into_iter()
+                .map(|n| {
+                    format!(
+                        "{{{{c{}::{}::{}}}}}",
+                        n,
+                        img(&note::cloze_page_name(n, true)),
+                        img(&note::cloze_page_name(n, false))
+                    )
+                })
+                .collect(),
+            None => img(&i.to_string()),
+        })
+        .collect()
+}
+
+/// Writes the jobs in the transaction and commits it; the journal covers the time until the config
+/// is saved.
+fn write_jobs<'a>(
+    jobs: impl IntoIterator<Item = &'a mut AnkiJob>,
+    notetypes: &Notetypes,
+    decks: &HashMap<Vec<String>, AnkiDeck>,
+    trans: Transaction,
+    config: &mut Config,
+    journal: &Journal,
+    snapshot: &mut SyncSnapshot,
+) -> Result<(), Error> {
+    let mut jobs: Vec<&mut AnkiJob> = jobs.into_iter().collect();
+    let mut entries = Vec::new();
+
+    for job in jobs.iter_mut() {
+        // a failing job only rolls back its own writes
+        trans.execute_batch("SAVEPOINT job")?;
+
+        let result = match &**job {
+            AnkiJob::Modify(note, ModifyAction::Add, JobState::Processing) => {
+                add_note_to_anki(note, notetypes, &decks[&note.subdeck], &trans, config)
+            }
+            AnkiJob::Modify(note, ModifyAction::Update, JobState::Processing) => {
+                update_note_in_anki(note, notetypes, &decks[&note.subdeck], &trans, config)
+            }
             AnkiJob::Delete(note_id, JobState::Processing) => {
-                delete_note_in_anki(note_id, &mut conn, &mut config)
+                delete_note_in_anki(note_id, &trans, config)
+            }
+            _ => {
+                trans.execute_batch("RELEASE job")?;
+                continue;
             }
-            _ => panic!("only delete jobs in list"),
         };
 
         match result {
-            Ok(_) => {
-                job.change_state(JobState::Success);
+            Ok(entry) => {
+                trans.execute_batch("RELEASE job")?;
+                entries.push(entry);
             }
             Err(Error::JobError(msg)) => {
+                trans.execute_batch("ROLLBACK TO job; RELEASE job")?;
                 job.change_state(JobState::Failed(msg));
             }
             Err(err) => Err(err)?,
         }
     }
 
-    monitor.update(&delete_jobs);
-    monitor.close();
+    snapshot.before_commit(&trans, config)?;
+    journal.record(&entries)?;
+    trans.commit()?;
+
+    for entry in &entries {
+        entry.apply(config);
+    }
+    config.write_back()?;
+    journal.clear()?;
 
-    clear_tmp_files()?;
+    for job in jobs.iter_mut() {
+        if let AnkiJob::Modify(_, _, JobState::Processing)
+        | AnkiJob::Delete(_, JobState::Processing) = &**job
+        {
+            job.change_state(JobState::Success);
+        }
+    }
+    Ok(())
+}
+
+/// Moves the images of the written notes into place and removes the ones of failed notes.
+fn finish_media<'a>(
+    jobs: impl IntoIterator<Item = &'a AnkiJob>,
+    context: &RenderContext,
+) -> Result<(), Error> {
+    for job in jobs {
+        match job {
+            AnkiJob::Modify(note, _, JobState::Success) => render::publish_media(note, context)?,
+            AnkiJob::Modify(note, _, JobState::Failed(_)) => render::discard_media(note, context)?,
+            _ => {}
+        }
+    }
     Ok(())
 }
 
 fn add_note_to_anki(
     note: &Note,
-    notetype: &AnkiNotetype,
+    notetypes: &Notetypes,
     deck: &AnkiDeck,
-    conn: &mut Connection,
-    config: &mut Config,
-) -> Result<(), Error> {
-    let trans = new_transaction(conn)?;
-
-    let mut ankinote = AnkiNote::new(notetype.get_id().expect("notetype not written do db"));
-
-    for i in 0..note.fields.len() {
-        let entry = format!(
-            "<img class=\"{}\" src=\"alas-{}-{}-{}.svg\">",
-            note.note_type.as_deref().unwrap_or("default"),
-            &config.anki_identifier,
-            note.id,
-            i
-        );
-        ankinote = ankinote.with_field_entry(&entry);
-    } // testing
-
-    let ankinote_id = ankinote
-        .generate_cards(&notetype, &deck)
-        .write_to_db(&trans)
+    trans: &Transaction,
+    config: &Config,
+) -> Result<JournalEntry, Error> {
+    let notetype = notetypes.of(note);
+    let ankinote = AnkiNote::new(notetype.get_id().expect("notetype not written do db"))
+        .with_guid(relink::note_guid(config, &note.id))
+        .with_fields(fit_fields(field_entries(note, config), notetype)?)
+        .with_tags(note.tags.clone());
+
+    let ankinote_id = generate_cards(ankinote, note, notetype, notetypes, deck, config)
+        .with_new_position(note.position)
+        .write_to_db(trans)
         .map_err(|_| Error::JobError("db error".to_string()))?;
 
-    config.store_ankinote_id(&note, ankinote_id);
-
-    trans.commit()?;
-    config.update_note_state(note);
-    config.write_back()?;
-    Ok(())
+    Ok(JournalEntry::Add {
+        note_id: note.id.clone(),
+        ankinote_id,
+        hash: config.note_hash(note),
+        deck_id: deck.get_id(),
+        tags: note.tags.clone(),
+    })
 }
 
 fn update_note_in_anki(
     note: &Note,
-    notetype: &AnkiNotetype,
+    notetypes: &Notetypes,
     deck: &AnkiDeck,
-    conn: &mut Connection,
-    config: &mut Config,
-) -> Result<(), Error> {
-    let trans = new_transaction(conn)?;
-
+    trans: &Transaction,
+    config: &Config,
+) -> Result<JournalEntry, Error> {
     let ankinote_id = config
         .get_ankinote_id(&note.id)
         .expect("inconsistent config");
 
-    let mut ankinote = AnkiNote::load_without_cards(&trans, ankinote_id)
+    let ankinote = AnkiNote::load_without_cards(trans, ankinote_id)
         .map_err(|_| Error::JobError("db error".to_string()))?
-        .ok_or_else(|| Error::JobError("not found".to_string()))?
-        .with_fields(vec![]);
-
-    for i in 0..note.fields.len() {
-        let entry = format!(
-            "<img class=\"{}\" src=\"alas-{}-{}-{}.svg\">",
-            note.note_type.as_deref().unwrap_or("default"),
-            &config.anki_identifier,
-            note.id,
-            i
-        );
-        ankinote = ankinote.with_field_entry(&entry);
+        .ok_or_else(|| Error::JobError("not found".to_string()))?;
+
+    // notes keep the notetype they were added with, e.g. when a note type is defined for the
+    // class of existing notes; only a cloze note cannot stay on another kind of notetype
+    let notetype = match notetypes.by_id(ankinote.get_notetype_id()) {
+        Some(notetype) if notetype.is_cloze() == (note.kind == NoteKind::Cloze) => notetype,
+        _ => {
+            return Err(Error::JobError(
+                "note type changed, remove the ID to add the note again".to_string(),
+            ))
+        }
+    };
+    let fields = fit_fields(field_entries(note, config), notetype)?;
+    // notes added by older versions of alas were matched by their images and get the guid now
+    let guid = relink::note_guid(config, &note.id);
+    let ankinote = match ankinote.get_guid() == guid {
+        true => ankinote,
+        false => ankinote.with_guid(guid),
     }
+    .with_fields(fields.clone());
 
+    // cards for new clozes or templates are added, existing cards keep their scheduling
+    let mut ankinote = generate_cards(ankinote, note, notetype, notetypes, deck, config)
+        .with_new_position(note.position);
     ankinote
-        .generate_cards(&notetype, &deck)
-        .write_to_db(&trans)
+        .write_to_db(trans)
         .map_err(|_| Error::JobError("db error".to_string()))?;
 
-    trans.commit()?;
-    config.update_note_state(note);
-    config.write_back()?;
-    Ok(())
+    Ok(JournalEntry::Update {
+        note_id: note.id.clone(),
+        ankinote_id,
+        fields,
+        hash: config.note_hash(note),
+        mtime: Some(ankinote.get_mtime()),
+    })
 }
 
 fn delete_note_in_anki(
     note_id: &str,
-    conn: &mut Connection,
-    config: &mut Config,
-) -> Result<(), Error> {
-    let trans = new_transaction(conn)?;
-
-    let ankinote_id = config.get_ankinote_id(&note_id).expect("consistent config");
-    let ankinote = AnkiNote::load_without_cards(&trans, ankinote_id)
+    trans: &Transaction,
+    config: &Config,
+) -> Result<JournalEntry, Error> {
+    let ankinote_id = config.get_ankinote_id(note_id).expect("consistent config");
+    let ankinote = AnkiNote::load_without_cards(trans, ankinote_id)
         .map_err(|_| Error::JobError("db error".to_string()))?
         .ok_or_else(|| Error::JobError("not found".to_string()))?;
 
+    let tags = ankinote.get_tags().to_vec();
     ankinote
-        .delete_with_cards(&trans)
+        .delete_with_cards(trans)
+        .and_then(|_| anki_db::remove_unused_tags(trans, &tags))
         .map_err(|_| Error::JobError("db error".to_string()))?;
 
-    trans.commit()?;
-    config.remove_note(&note_id);
-    config.write_back()?;
-    Ok(())
+    Ok(JournalEntry::Delete {
+        note_id: note_id.to_string(),
+        ankinote_id,
+    })
 }
 
-fn generate_tmp_svg_files_for_batch(batch: &Vec<&Note>) -> Result<(), Error> {
-    // TODO can one field exceed one page?
-    let compiled = batch
-        .iter()
-        .flat_map(|note| &note.fields)
-        .map(|field| field.replace("\\newpage", ""))
-        .fold(String::from(""), |acc, f| format!("{acc} \n\\newpage {f}")); // TODO is format! efficient?
-
-    let latex = include_str!("../templates/latex/skeleton.tex").replace("{{content}}", &compiled);
-
-    fs::write("tmp.tex", latex)?;
-
-    // generate dvi file
-    Command::new("latex")
-        .args(&["-interaction=nonstopmode", "tmp.tex"])
-        .output()?;
-
-    // generate one svg file for each page
-    Command::new("dvisvgm")
-        .args(&[
-            "--no-fonts",
-            "-Z",
-            "2",
-            "tmp.dvi",
-            "--page=1-",
-            "-o",
-            "tmp-%3p.svg",
-        ])
-        .output()?;
-
-    // assume rendering was successfull if there are 2 * #notes files
-    // TODO can i identify the page which failed? is there a option to skip this page number?
-    let last_file = format!("tmp-{:03}.svg", batch.len() * 2);
-    if !PathBuf::from(&last_file).exists() {
-        return Err(Error::JobError("failed rendering".to_string()));
+/// Generates the cards for the clozes, the templates of a project notetype or the selected
+/// templates of the basic notetype.
+fn generate_cards(
+    ankinote: AnkiNote,
+    note: &Note,
+    notetype: &AnkiNotetype,
+    notetypes: &Notetypes,
+    deck: &AnkiDeck,
+    config: &Config,
+) -> AnkiNote {
+    // the templates of the basic notetype are chosen by the options of the note
+    if notetype.get_id() == notetypes.basic.get_id() {
+        ankinote.generate_cards_for_templates(
+            deck,
+            &notetype::basic_template_ords(
+                notetype,
+                config,
+                note.options.cards.unwrap_or_default(),
+            ),
+        )
+    } else {
+        ankinote.generate_cards(notetype, deck)
     }
+}
 
-    Ok(())
+/// Fills missing fields of the notetype with empty ones.
+fn fit_fields(mut fields: Vec<String>, notetype: &AnkiNotetype) -> Result<Vec<String>, Error> {
+    if fields.len() > notetype.num_fields() {
+        return Err(Error::JobError(format!(
+            "{} fields, note type has {}",
+            fields.len(),
+            notetype.num_fields()
+        )));
+    }
+    fields.resize(notetype.num_fields(), String::new());
+    Ok(fields)
 }
 
-fn move_tmp_svg_files_to_anki_media(
-    notes: &Vec<&Note>,
-    anki_media_dir: &PathBuf,
-    identifier: &str,
-) -> Result<(), Error> {
-    let mut i = 1;
-    for note in notes {
-        for j in 0..note.fields.len() {
-            let src = format!("tmp-{:03}.svg", i); // TODO batch larger than 99?
-            let dest_file = format!("alas-{}-{}-{}.svg", identifier, note.id, j);
-            let dest = anki_media_dir.join(dest_file);
-            fs::copy(&src, &dest)?;
-            fs::remove_file(&src)?;
-            i += 1;
-        }
+/// Creates the notetypes defined in the project and updates them when their definition changed.
+fn sync_project_notetypes(
+    config: &mut Config,
+    trans: &Transaction,
+) -> Result<HashMap<String, AnkiNotetype>, Error> {
+    let mut notetypes = HashMap::new();
+
+    for project_notetype in ProjectNotetype::load_all(&config.alas_dir())? {
+        let desired = project_notetype.build(config);
+        let existing = match config.anki_notetypes.get(&project_notetype.name) {
+            Some(id) => AnkiNotetype::load(*id, trans)?,
+            None => None,
+        };
+
+        let notetype = match existing {
+            Some(mut notetype) => {
+                notetype::update_notetype(&mut notetype, &desired, trans)?;
+                notetype
+            }
+            // notetypes deleted in anki are created again
+            None => {
+                let mut notetype = desired;
+                let id = notetype.write_to_db(trans)?;
+                config
+                    .anki_notetypes
+                    .insert(project_notetype.name.clone(), id);
+                notetype
+            }
+        };
+        notetypes.insert(project_notetype.name, notetype);
     }
-    Ok(())
+
+    Ok(notetypes)
 }
 
-fn clear_tmp_files() -> Result<(), std::io::Error> {
-    for entry in fs::read_dir(".")? {
-        let entry = entry?;
-        let path = entry.path();
-        if path
-            .file_name()
-            .and_then(|name| name.to_str())
-            .map_or(false, |name| {
-                name.starts_with("tmp-") && name.ends_with(".svg")
-            })
-        {
-            fs::remove_file(path)?;
-        }
+/// Loads and updates the cloze notetype of the project, creating it on first use.
+fn get_or_create_cloze_notetype(
+    config: &mut Config,
+    trans: &Transaction,
+) -> Result<AnkiNotetype, Error> {
+    let desired = notetype::cloze_notetype(config)?;
+    if let Some(id) = config.anki_cloze_notetype_id {
+        let mut notetype = AnkiNotetype::load(id, trans)?.ok_or_else(|| {
+            Error::AlasError("Anki cloze notetype could not be found.".to_string())
+        })?;
+        notetype::update_notetype(&mut notetype, &desired, trans)?;
+        return Ok(notetype);
     }
-    fs::remove_file("tmp.aux").ok();
-    fs::remove_file("tmp.log").ok();
-    fs::remove_file("tmp.tex").ok();
-    fs::remove_file("tmp.dvi").ok();
-    Ok(())
+
+    let mut notetype = desired;
+    config.anki_cloze_notetype_id = Some(notetype.write_to_db(trans)?);
+    Ok(notetype)
 }
 
-fn find_tex_files_in_current_dir() -> Result<Vec<PathBuf>, Error> {
-    let mut tex_files = Vec::new();
-    for entry in fs::read_dir(".")? {
-        let entry = entry?;
-        let path = entry.path();
-        if path.extension().and_then(|ext| ext.to_str()) == Some("tex") {
-            tex_files.push(path);
+/// Applies the deck options to the deck config of the project deck and returns whether they changed
+/// it. The deck config of a deck in anki is only written if no deck outside the project uses it.
+fn update_deck_options(
+    deck: &mut AnkiDeck,
+    options: &DeckOptions,
+    trans: &Transaction,
+) -> Result<bool, Error> {
+    let mut deck_conf = deck.conf.clone();
+    options.apply(&mut deck_conf.inner)?;
+    if deck_conf == deck.conf {
+        return Ok(false);
+    }
+
+    if let Some(dcid) = deck_conf.get_id() {
+        let subdeck_prefix = format!("{}\x1f", deck.name);
+        let others = AnkiDeck::names_using_config(dcid, trans)?
+            .into_iter()
+            .filter(|name| name != &deck.name && !name.starts_with(&subdeck_prefix))
+            .map(|name| format!("'{}'", name.replace('\x1f', "::")))
+            .collect::<Vec<_>>();
+        if !others.is_empty() {
+            return Err(Error::ConfigError(format!(
+                "The options preset '{}' of deck '{}' is also used by {}. Select a preset used only by the decks of the project in Anki to apply deck_options.",
+                deck_conf.name,
+                deck.name.replace('\x1f', "::"),
+                others.join(", ")
+            )));
         }
+        deck_conf.write_to_db(trans)?;
+    }
+    deck.conf = deck_conf;
+    Ok(true)
+}
+
+/// Returns the subdeck of the project deck at `path`, creating missing decks on the way.
+fn get_or_create_deck(
+    root: &AnkiDeck,
+    path: &[String],
+    trans: &Transaction,
+) -> Result<AnkiDeck, Error> {
+    let mut deck = root.clone();
+    for name in path {
+        let child = deck.new_child(name);
+        deck = match AnkiDeck::load_by_name(&child.name, trans)? {
+            Some(existing) => existing,
+            None => {
+                let mut child = child;
+                child.write_to_db(trans)?;
+                child
+            }
+        };
     }
-    Ok(tex_files)
+    Ok(deck)
+}
+
+/// Opens the anki collection and keeps it locked until the connection is closed, like anki does.
+fn open_collection(config: &Config) -> Result<Connection, Error> {
+    let anki_db_path = config.anki_collection_path();
+
+    // opening a missing database would create an empty one
+    if !anki_db_path.exists() {
+        return Err(Error::AlasError(format!(
+            "Anki collection '{}' does not exist.",
+            anki_db_path.display()
+        )));
+    }
+
+    let conn = Connection::open(&anki_db_path)
+        .map_err(|_| Error::AlasError("Failed to open an anki database connection.".to_string()))?;
+
+    conn.create_collation("unicase", |lhs: &str, rhs: &str| {
+        UniCase::new(lhs).cmp(&UniCase::new(rhs))
+    })
+    .expect("can register unicase");
+
+    // sqlite retries until the timeout if the collection is busy
+    conn.busy_timeout(Duration::from_secs(config.busy_timeout))?;
+
+    conn.query_row("PRAGMA locking_mode = EXCLUSIVE", [], |_| Ok(()))?;
+    conn.execute_batch("BEGIN EXCLUSIVE; COMMIT;")
+        .map_err(|err| match err {
+            rusqlite::Error::SqliteFailure(e, _)
+                if matches!(e.code, ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked) =>
+            {
+                Error::AlasError(
+                    "The Anki collection is in use. Close Anki and try again.".to_string(),
+                )
+            }
+            err => Error::DbError(err),
+        })?;
+
+    Ok(conn)
 }
 
 fn new_transaction(conn: &mut Connection) -> Result<Transaction, Error> {
@@ -436,6 +1223,20 @@ fn new_transaction(conn: &mut Connection) -> Result<Transaction, Error> {
         .map_err(|_| Error::AlasError("Failed to create an anki database transaction.".to_string()))
 }
 
+/// Returns the pending transaction or a new one; a sync writes in several transactions on the same
+/// connection one after the other.
+fn next_transaction<'c>(
+    pending: &mut Option<Transaction<'c>>,
+    conn: &'c Connection,
+) -> Result<Transaction<'c>, Error> {
+    match pending.take() {
+        Some(trans) => Ok(trans),
+        None => conn.unchecked_transaction().map_err(|_| {
+            Error::AlasError("Failed to create an anki database transaction.".to_string())
+        }),
+    }
+}
+
 static TEMPLATE_DIR: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/templates/project_dir");
 
 fn init_project_dir() -> Result<(), Error> {
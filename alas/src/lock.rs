@@ -0,0 +1,50 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    path::Path,
+    process,
+};
+
+use fs2::FileExt;
+
+use crate::error::Error;
+
+/// Advisory lock which keeps two alas processes from working on the same project at once.
+///
+/// The lock is held by the operating system on the open file `.alas/lock`, so it is released when
+/// the process exits, even after a crash. The file itself stays and only records the last pid.
+pub struct ProjectLock {
+    _file: File,
+}
+
+impl ProjectLock {
+    pub fn acquire(alas_dir: &Path) -> Result<Self, Error> {
+        let path = alas_dir.join("lock");
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        if let Err(err) = file.try_lock_exclusive() {
+            if err.kind() != fs2::lock_contended_error().kind() {
+                return Err(err.into());
+            }
+            // the pid can't be read on systems where locks are mandatory
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            return Err(Error::AlasError(match pid.trim() {
+                "" => "Another alas process is working on this project.".to_string(),
+                pid => format!(
+                    "Another alas process (pid {}) is working on this project.",
+                    pid
+                ),
+            }));
+        }
+
+        file.set_len(0)?;
+        write!(file, "{}", process::id())?;
+        Ok(Self { _file: file })
+    }
+}
@@ -3,9 +3,9 @@ use std::process::Command;
 
 use alas::{
     error::handle_error,
-    init_dir,
+    init_dir, list_snapshots,
     messages::{print_message, MessageType},
-    sync_notes,
+    print_status, relink, required_programs, restore, sync_notes,
 };
 
 #[derive(Parser)]
@@ -27,17 +27,51 @@ enum Commands {
         identifier: Option<String>,
         #[arg(short, long, help = "Add template .tex files", default_value_t = false)]
         files: bool,
+        #[arg(
+            short,
+            long,
+            help = "Reuse an existing deck and note types with the same names",
+            default_value_t = false
+        )]
+        adopt: bool,
     },
     #[command(about = "Sync all your LaTeX notes with Anki")]
     Sync {
         #[arg(short, long, help = "Specify the batch size", default_value = "9")]
         batch_size: usize,
+        #[arg(
+            short,
+            long,
+            help = "Specify the number of parallel render workers [default: number of CPUs]"
+        )]
+        jobs: Option<usize>,
+        #[arg(
+            long,
+            help = "Only show pending changes without touching Anki",
+            default_value_t = false
+        )]
+        dry_run: bool,
+        #[arg(
+            long,
+            help = "Write all changes in a single transaction after rendering",
+            default_value_t = false
+        )]
+        atomic: bool,
     },
+    #[command(about = "Show pending changes without touching Anki")]
+    Status,
+    #[command(about = "Restore the Anki collection and the config from a backup")]
+    Restore {
+        #[arg(help = "Specify the snapshot to restore [default: latest]")]
+        snapshot: Option<String>,
+        #[arg(short, long, help = "List all snapshots", default_value_t = false)]
+        list: bool,
+    },
+    #[command(about = "Link the project to its deck and notes in the Anki collection")]
+    Relink,
 }
 
 fn main() {
-    check_required_programms();
-
     let cli = Cli::parse();
     if let Err(err) = match cli.command {
         Commands::Init {
@@ -45,17 +79,35 @@ fn main() {
             deck,
             identifier,
             files,
-        } => init_dir(profile, deck, identifier, files),
-        Commands::Sync { batch_size } => sync_notes(batch_size),
+            adopt,
+        } => init_dir(profile, deck, identifier, files, adopt),
+        Commands::Sync {
+            batch_size,
+            jobs,
+            dry_run: false,
+            atomic,
+        } => required_programs().and_then(|programs| {
+            check_required_programms(&programs);
+            sync_notes(batch_size, jobs, atomic)
+        }),
+        Commands::Sync { dry_run: true, .. } | Commands::Status => {
+            // errors exit with 1, so scripts can tell them apart from pending changes
+            print_status().map(|pending| {
+                if pending {
+                    std::process::exit(2);
+                }
+            })
+        }
+        Commands::Restore { list: true, .. } => list_snapshots(),
+        Commands::Restore { snapshot, .. } => restore(snapshot),
+        Commands::Relink => relink(),
     } {
         handle_error(err);
         std::process::exit(1);
     }
 }
 
-fn check_required_programms() {
-    let required_programs = vec!["latex", "dvisvgm"];
-
+fn check_required_programms(required_programs: &[&str]) {
     for program in required_programs {
         if !check_program_installed(program) {
             print_message(
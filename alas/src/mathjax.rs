@@ -0,0 +1,338 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::HashSet;
+
+lazy_static! {
+    static ref MACRO_DEF_RE: Regex = Regex::new(
+        r"\\(?:(?:re|provide)?newcommand\*?|DeclareMathOperator\*?|def)\s*\{?\\([A-Za-z]+)"
+    )
+    .unwrap();
+    static ref MACRO_USE_RE: Regex = Regex::new(r"\\([A-Za-z]+)").unwrap();
+    static ref LABEL_RE: Regex = Regex::new(r"\\label\s*\{[^}]*\}").unwrap();
+}
+
+/// math environments and the environment they become inside of \[...\]
+const MATH_ENVS: [(&str, Option<&str>); 7] = [
+    ("equation", None),
+    ("equation*", None),
+    ("align", Some("aligned")),
+    ("align*", Some("aligned")),
+    ("gather", Some("gathered")),
+    ("gather*", Some("gathered")),
+    ("displaymath", None),
+];
+
+const LIST_ENVS: [(&str, &str); 2] = [("itemize", "ul"), ("enumerate", "ol")];
+
+const TEXT_COMMANDS: [(&str, &str); 5] = [
+    ("textbf", "b"),
+    ("textit", "i"),
+    ("emph", "i"),
+    ("underline", "u"),
+    ("texttt", "code"),
+];
+
+const SIMPLE_COMMANDS: [(&str, &str); 8] = [
+    ("par", "<br>"),
+    ("newline", "<br>"),
+    ("noindent", ""),
+    ("quad", "&emsp;"),
+    ("qquad", "&emsp;&emsp;"),
+    ("ldots", "&hellip;"),
+    ("dots", "&hellip;"),
+    ("LaTeX", "LaTeX"),
+];
+
+/// Returns the macros defined in the preamble, which MathJax does not know about.
+pub fn user_macros(preamble: &str) -> HashSet<String> {
+    MACRO_DEF_RE
+        .captures_iter(preamble)
+        .map(|cap| cap.get(1).unwrap().as_str().to_string())
+        .collect()
+}
+
+/// Converts a field to HTML with MathJax delimiters; `None` if the field needs latex to render.
+pub fn to_html(latex: &str, user_macros: &HashSet<String>) -> Option<String> {
+    let mut html = String::new();
+    convert_text(latex, user_macros, &mut html)?;
+    Some(html.trim().to_string())
+}
+
+fn convert_text(latex: &str, user_macros: &HashSet<String>, html: &mut String) -> Option<()> {
+    let mut rest = latex;
+
+    while let Some(c) = rest.chars().next() {
+        if let Some(r) = rest.strip_prefix("$$") {
+            let end = r.find("$$")?;
+            push_math(&r[..end], true, user_macros, html)?;
+            rest = &r[end + 2..];
+        } else if let Some(r) = rest.strip_prefix('$') {
+            let end = find_unescaped(r, '$')?;
+            push_math(&r[..end], false, user_macros, html)?;
+            rest = &r[end + 1..];
+        } else if let Some(r) = rest.strip_prefix("\\(") {
+            let end = r.find("\\)")?;
+            push_math(&r[..end], false, user_macros, html)?;
+            rest = &r[end + 2..];
+        } else if let Some(r) = rest.strip_prefix("\\[") {
+            let end = r.find("\\]")?;
+            push_math(&r[..end], true, user_macros, html)?;
+            rest = &r[end + 2..];
+        } else if let Some(r) = rest.strip_prefix('\\') {
+            rest = convert_command(r, user_macros, html)?;
+        } else if c == '%' {
+            // skip comments including the line break
+            rest = rest.find('\n').map_or("", |end| &rest[end + 1..]);
+        } else if c == '\n' {
+            // an empty line starts a new paragraph
+            let trimmed = rest[1..].trim_start_matches([' ', '\t', '\r']);
+            if trimmed.starts_with('\n') {
+                html.push_str("<br>");
+                rest = trimmed.trim_start();
+            } else {
+                html.push(' ');
+                rest = trimmed;
+            }
+        } else if let Some(r) = rest.strip_prefix("---") {
+            html.push_str("&mdash;");
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("--") {
+            html.push_str("&ndash;");
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("``") {
+            html.push_str("&ldquo;");
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("''") {
+            html.push_str("&rdquo;");
+            rest = r;
+        } else {
+            match c {
+                // groups only limit the scope of commands
+                '{' | '}' => (),
+                // only allowed in math, latex reports them
+                '_' | '^' | '#' | '&' => return None,
+                '~' => html.push_str("&nbsp;"),
+                '<' => html.push_str("&lt;"),
+                '>' => html.push_str("&gt;"),
+                _ => html.push(c),
+            }
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    Some(())
+}
+
+/// Converts the command at the start of `latex` (without backslash) and returns the remaining text.
+fn convert_command<'a>(
+    latex: &'a str,
+    user_macros: &HashSet<String>,
+    html: &mut String,
+) -> Option<&'a str> {
+    let name_len = latex
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(latex.len());
+
+    // control symbols
+    if name_len == 0 {
+        let c = latex.chars().next()?;
+        let rest = &latex[c.len_utf8()..];
+        match c {
+            '\\' => html.push_str("<br>"),
+            '%' | '$' | '#' | '_' | '{' | '}' => html.push(c),
+            '&' => html.push_str("&amp;"),
+            ' ' => html.push(' '),
+            ',' => html.push_str("&thinsp;"),
+            _ => return None,
+        }
+        return Some(rest);
+    }
+
+    let name = &latex[..name_len];
+    let rest = &latex[name_len..];
+
+    match name {
+        "begin" => {
+            let (env, rest) = read_group(rest)?;
+
+            if let Some((_, inner_env)) = MATH_ENVS.iter().find(|(e, _)| *e == env) {
+                let end_tag = format!("\\end{{{}}}", env);
+                let end = rest.find(&end_tag)?;
+                let math = match inner_env {
+                    Some(inner_env) => format!(
+                        "\\begin{{{}}}{}\\end{{{}}}",
+                        inner_env,
+                        &rest[..end],
+                        inner_env
+                    ),
+                    None => rest[..end].to_string(),
+                };
+                push_math(&math, true, user_macros, html)?;
+                return Some(&rest[end + end_tag.len()..]);
+            }
+
+            let (_, tag) = LIST_ENVS.iter().find(|(e, _)| *e == env)?;
+            html.push_str(&format!("<{}>", tag));
+            Some(rest)
+        }
+        "end" => {
+            let (env, rest) = read_group(rest)?;
+            let (_, tag) = LIST_ENVS.iter().find(|(e, _)| *e == env)?;
+            html.push_str(&format!("</{}>", tag));
+            Some(rest)
+        }
+        "item" => {
+            // custom item labels are not supported
+            let rest = rest.trim_start();
+            if rest.starts_with('[') {
+                return None;
+            }
+            html.push_str("<li>");
+            Some(rest)
+        }
+        _ => {
+            if let Some((_, tag)) = TEXT_COMMANDS.iter().find(|(c, _)| *c == name) {
+                let (arg, rest) = read_group(rest)?;
+                html.push_str(&format!("<{}>", tag));
+                convert_text(arg, user_macros, html)?;
+                html.push_str(&format!("</{}>", tag));
+                return Some(rest);
+            }
+
+            let (_, replacement) = SIMPLE_COMMANDS.iter().find(|(c, _)| *c == name)?;
+            html.push_str(replacement);
+
+            // spaces after control words are ignored by latex
+            Some(rest.trim_start_matches([' ', '\t']))
+        }
+    }
+}
+
+fn push_math(
+    math: &str,
+    display: bool,
+    user_macros: &HashSet<String>,
+    html: &mut String,
+) -> Option<()> {
+    // MathJax does not know the macros defined in the preamble
+    if MACRO_USE_RE
+        .captures_iter(math)
+        .any(|cap| user_macros.contains(cap.get(1).unwrap().as_str()))
+    {
+        return None;
+    }
+
+    let math = LABEL_RE.replace_all(math, "");
+    let math = math
+        .trim()
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+
+    if display {
+        html.push_str(&format!("\\[{}\\]", math));
+    } else {
+        html.push_str(&format!("\\({}\\)", math));
+    }
+    Some(())
+}
+
+fn find_unescaped(text: &str, pat: char) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            c if c == pat && !escaped => return Some(i),
+            '\\' => escaped = !escaped,
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
+/// Splits `{group}rest` into the group's content and the rest.
+fn read_group(text: &str) -> Option<(&str, &str)> {
+    let text = text.trim_start();
+    if !text.starts_with('{') {
+        return None;
+    }
+
+    let mut depth = 0;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '{' if !escaped => depth += 1,
+            '}' if !escaped => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((&text[1..i], &text[i + 1..]));
+                }
+            }
+            _ => (),
+        }
+        escaped = c == '\\' && !escaped;
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn convert(latex: &str) -> Option<String> {
+        to_html(latex, &HashSet::new())
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(convert("a {b} c").as_deref(), Some("a b c"));
+        assert_eq!(
+            convert(r"\{x\} \_ \# \& 50\%").as_deref(),
+            Some("{x} _ # &amp; 50%")
+        );
+        assert_eq!(
+            convert(r"\textbf{bold {and} \emph{it}} -- x<y").as_deref(),
+            Some("<b>bold and <i>it</i></b> &ndash; x&lt;y")
+        );
+        assert_eq!(
+            convert("one\n\ntwo\nthree").as_deref(),
+            Some("one<br>two three")
+        );
+        assert_eq!(
+            convert("shown % hidden\nnext").as_deref(),
+            Some("shown next")
+        );
+    }
+
+    #[test]
+    fn special_characters() {
+        // latex only accepts them in math
+        assert_eq!(convert("a_b"), None);
+        assert_eq!(convert("a^b"), None);
+        assert_eq!(convert("#1"), None);
+        assert_eq!(convert("a & b"), None);
+        assert_eq!(convert("$a_b^{#}$").as_deref(), Some(r"\(a_b^{#}\)"));
+    }
+
+    #[test]
+    fn math() {
+        assert_eq!(convert(r"$x < 1$").as_deref(), Some(r"\(x &lt; 1\)"));
+        assert_eq!(convert(r"$$x$$ \[y\]").as_deref(), Some(r"\[x\] \[y\]"));
+        assert_eq!(
+            convert(r"\begin{align*}a &= b\label{eq}\end{align*}").as_deref(),
+            Some(r"\[\begin{aligned}a &amp;= b\end{aligned}\]")
+        );
+        assert_eq!(convert(r"$x"), None);
+    }
+
+    #[test]
+    fn unsupported() {
+        assert_eq!(convert(r"\includegraphics{plot}"), None);
+        assert_eq!(convert(r"\item[a] b"), None);
+        assert_eq!(convert(r"\begin{tabular}{c}\end{tabular}"), None);
+
+        let macros = user_macros(r"\newcommand{\R}{\mathbb{R}} \DeclareMathOperator{\im}{im}");
+        assert_eq!(to_html(r"$x \in \R$", &macros), None);
+        assert_eq!(to_html(r"$\im f$", &macros), None);
+        assert!(to_html(r"$\Rightarrow$", &macros).is_some());
+    }
+}
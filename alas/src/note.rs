@@ -1,47 +1,371 @@
 use lazy_static::lazy_static;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::{
-    fs::{File, OpenOptions},
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
     io::{BufReader, Read, Write},
     path::PathBuf,
 };
 use uuid::Uuid;
 
+use crate::error::Error;
+use crate::mathjax;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputMode {
+    #[default]
+    Image,
+    Mathjax,
+}
+
+/// Environment a note is written in: `note` with front and back fields or `cloze` with clozes
+/// like `\cloze{1}{...}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum NoteKind {
+    #[default]
+    Basic,
+    Cloze,
+}
+
+/// Cards of a note: `front` asks for the back, `reverse` (or `both`) adds a second card asking for
+/// the front.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CardMode {
+    #[default]
+    Front,
+    Reverse,
+}
+
+impl CardMode {
+    /// Returns the indices of the templates of the basic notetype; the front card is always
+    /// included, anki would create it for a note with only the reverse card anyway.
+    pub fn template_idxs(&self) -> &'static [u16] {
+        match self {
+            CardMode::Front => &[0],
+            CardMode::Reverse => &[0, 1],
+        }
+    }
+}
+
+/// Options given in `\begin{note}[...]`, e.g. `[definition, mathjax]` or `[type=definition, cards=reverse, tags={limits,exam}]`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NoteOptions {
+    pub note_type: Option<String>,
+    pub output: Option<OutputMode>,
+    pub cards: Option<CardMode>,
+    pub tags: Vec<String>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Note {
     pub id: String,
-    pub note_type: Option<String>,
+    pub kind: NoteKind,
+    pub options: NoteOptions,
     pub fields: Vec<String>,
+    /// html for fields shown with MathJax; `None` for fields rendered to images
+    pub html_fields: Vec<Option<String>>,
+    pub field_lines: Vec<usize>,
+    pub file: PathBuf,
+    /// line of `\begin{note}`
+    pub line: usize,
+    /// position of new cards in the new card queue of the collection
+    pub position: u32,
+    /// chapter and section the note is in, e.g. `Chapter 3 Sequences`
+    pub chapter: Option<String>,
+    pub section: Option<String>,
+    /// path of the subdeck below the project deck
+    pub subdeck: Vec<String>,
+    /// tags of the note option and of the file, sorted
+    pub tags: Vec<String>,
+}
+
+/// Latex of a note rendered to one image.
+pub struct Page {
+    /// field the page is shown in
+    pub field: usize,
+    /// part of the media file name which identifies the page within the note
+    pub name: String,
+    pub latex: String,
 }
 
 impl Note {
-    pub fn hash_text(&self) -> String {
+    /// Hashes everything the rendered output depends on; `fingerprint` covers the render setup.
+    pub fn hash_text(&self, fingerprint: &str) -> String {
         let mut hasher = Sha256::new();
         let concatenated_fields = self.fields.join("|");
         hasher.update(concatenated_fields.as_bytes());
+        hasher.update(b"|");
+        hasher.update(
+            self.options
+                .note_type
+                .as_deref()
+                .unwrap_or_default()
+                .as_bytes(),
+        );
+        hasher.update(b"|");
+        hasher.update(format!("{:?}", self.options.output).as_bytes());
+        hasher.update(b"|");
+        if self.kind == NoteKind::Cloze {
+            hasher.update(b"cloze|");
+        }
+        // notes get new cards when the option changes
+        if let Some(cards) = self.options.cards {
+            hasher.update(format!("{:?}|", cards).as_bytes());
+        }
+        hasher.update(fingerprint.as_bytes());
         format!("{:x}", hasher.finalize())
     }
+
+    /// Decides for each field whether it is shown with MathJax or rendered to an image.
+    pub fn resolve_outputs(&mut self, default_output: OutputMode, user_macros: &HashSet<String>) {
+        let output = self.options.output.unwrap_or(default_output);
+        self.html_fields = self
+            .fields
+            .iter()
+            .enumerate()
+            .map(|(i, field)| match output {
+                // clozes are rendered to one image per cloze number
+                OutputMode::Mathjax if !(self.kind == NoteKind::Cloze && i == 0) => {
+                    mathjax::to_html(field, user_macros)
+                }
+                _ => None,
+            })
+            .collect();
+    }
+
+    /// Adds tags for the file, chapter and section of the note.
+    pub fn add_structure_tags(&mut self) {
+        let file = self
+            .file
+            .file_stem()
+            .map(|f| f.to_string_lossy().to_string());
+        let structure = [file, self.chapter.clone(), self.section.clone()];
+
+        self.tags.extend(
+            structure
+                .into_iter()
+                .flatten()
+                .map(|name| name.split_whitespace().collect::<Vec<_>>().join("_")),
+        );
+        self.tags.sort();
+        self.tags.dedup();
+    }
+
+    /// Returns the indices and contents of the fields rendered to images.
+    pub fn image_fields(&self) -> impl Iterator<Item = (usize, &String)> {
+        self.fields
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !matches!(self.html_fields.get(*i), Some(Some(_))))
+    }
+
+    /// Returns the pages rendered for the note: one per image field, and for the cloze field a
+    /// question and an answer page per cloze number.
+    pub fn pages(&self) -> Vec<Page> {
+        let mut pages = Vec::new();
+        for (i, field) in self.image_fields() {
+            if self.kind == NoteKind::Cloze && i == 0 {
+                for ordinal in self.cloze_ordinals() {
+                    for answer in [false, true] {
+                        pages.push(Page {
+                            field: i,
+                            name: cloze_page_name(ordinal, answer),
+                            latex: cloze_latex(field, ordinal, answer),
+                        });
+                    }
+                }
+            } else {
+                pages.push(Page {
+                    field: i,
+                    name: i.to_string(),
+                    latex: field.clone(),
+                });
+            }
+        }
+        pages
+    }
+
+    /// Checks that anki can create cards for the note; the error starts with the file and line of
+    /// the problem.
+    pub fn check(&self) -> Result<(), String> {
+        if self.kind == NoteKind::Cloze && self.cloze_ordinals().is_empty() {
+            return Err(format!(
+                "{}:{}: cloze note {} contains no \\cloze{{n}}{{...}}",
+                self.file.display(),
+                self.field_lines.first().copied().unwrap_or(self.line),
+                self.id
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the cloze numbers used in the first field, sorted.
+    pub fn cloze_ordinals(&self) -> Vec<u32> {
+        let mut ordinals: Vec<u32> = match (self.kind, self.fields.first()) {
+            (NoteKind::Cloze, Some(text)) => CLOZE_RE
+                .captures_iter(text)
+                .filter_map(|cap| cap[1].parse().ok())
+                .filter(|n| *n > 0)
+                .collect(),
+            _ => Vec::new(),
+        };
+        ordinals.sort();
+        ordinals.dedup();
+        ordinals
+    }
+}
+
+impl NoteOptions {
+    pub fn parse(raw: &str) -> Self {
+        let mut options = NoteOptions::default();
+
+        for option in split_options(raw) {
+            match option.split_once('=') {
+                Some((key, value)) => {
+                    let value = value.trim().trim_start_matches('{').trim_end_matches('}');
+                    match key.trim() {
+                        "type" => options.note_type = Some(value.trim().to_string()),
+                        "output" => options.output = parse_output(value.trim()),
+                        "cards" => options.cards = parse_cards(value.trim()),
+                        "tags" => options.tags = split_tags(value),
+                        _ => (),
+                    }
+                }
+                None => match option {
+                    "mathjax" | "image" => options.output = parse_output(option),
+                    "front" | "reverse" | "both" => options.cards = parse_cards(option),
+                    _ if options.note_type.is_none() => {
+                        options.note_type = Some(option.to_string())
+                    }
+                    _ => (),
+                },
+            }
+        }
+
+        options
+    }
+}
+
+fn parse_output(value: &str) -> Option<OutputMode> {
+    match value {
+        "mathjax" => Some(OutputMode::Mathjax),
+        "image" => Some(OutputMode::Image),
+        _ => None,
+    }
+}
+
+fn parse_cards(value: &str) -> Option<CardMode> {
+    match value {
+        "front" => Some(CardMode::Front),
+        "reverse" | "both" => Some(CardMode::Reverse),
+        _ => None,
+    }
+}
+
+/// Returns the name of the question or answer page of a cloze number.
+pub fn cloze_page_name(ordinal: u32, answer: bool) -> String {
+    format!("0-c{}{}", ordinal, if answer { "a" } else { "q" })
+}
+
+/// Hides the clozes with number `active` in the question and highlights them in the answer; all
+/// other clozes are shown as plain text.
+fn cloze_latex(text: &str, active: u32, answer: bool) -> String {
+    let mut latex = String::new();
+    let mut rest = text;
+
+    while let Some(cap) = CLOZE_RE.captures(rest) {
+        let m = cap.get(0).unwrap();
+        latex.push_str(&rest[..m.start()]);
+
+        // find the closing brace of the content
+        let content_start = m.end();
+        let mut depth = 1;
+        let mut content_end = rest.len();
+        for (i, c) in rest[content_start..].char_indices() {
+            match c {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => (),
+            }
+            if depth == 0 {
+                content_end = content_start + i;
+                break;
+            }
+        }
+
+        let content = cloze_latex(&rest[content_start..content_end], active, answer);
+        let macro_name = match cap[1].parse::<u32>() {
+            Ok(n) if n == active && answer => "\\alasclozeshown",
+            Ok(n) if n == active => "\\alasclozehidden",
+            _ => "",
+        };
+        latex.push_str(&format!("{}{{{}}}", macro_name, content));
+        rest = &rest[(content_end + 1).min(rest.len())..];
+    }
+
+    latex.push_str(rest);
+    latex
+}
+
+/// Splits a list of tags like `limits, exam` or `limits exam`; anki tags cannot contain spaces.
+fn split_tags(raw: &str) -> Vec<String> {
+    raw.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|tag| !tag.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Splits at commas which are not inside of braces.
+fn split_options(raw: &str) -> Vec<&str> {
+    let mut options = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (i, c) in raw.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ',' if depth == 0 => {
+                options.push(raw[start..i].trim());
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    options.push(raw[start..].trim());
+
+    options.into_iter().filter(|o| !o.is_empty()).collect()
 }
 
 lazy_static! {
-    static ref NOTE_RE: Regex =
-        Regex::new(r"(?sm)^[^%\n]*?\\begin\{note\}(?:\[(.*?)\])?(.*?)\\end\{note\}").unwrap();
+    static ref NOTE_RE: Regex = Regex::new(
+        r"(?sm)^[^%\n]*?\\begin\{(note|cloze)\}(?:\[(.*?)\])?(.*?)\\end\{(?:note|cloze)\}"
+    )
+    .unwrap();
     static ref FIELD_RE: Regex =
         Regex::new(r"(?sm)^[^%\n]*?\\begin\{field\}\s*(.*?)\s*\\end\{field\}").unwrap();
     static ref ID_RE: Regex = Regex::new(r"%\s*ID:\s*([^\r\n]*)").unwrap();
+    static ref CLOZE_RE: Regex = Regex::new(r"\\cloze\{\s*(\d+)\s*\}\{").unwrap();
+    static ref FILE_TAGS_RE: Regex = Regex::new(r"(?m)^\s*%\s*alas-tags:(.*)$").unwrap();
 }
 
-pub fn parse_tex_file(path: &PathBuf) -> Vec<Note> {
-    let file = File::open(&path).expect("Could not open file");
+pub fn parse_tex_file(path: &PathBuf) -> Result<Vec<Note>, Error> {
+    let file = File::open(path)?;
     let mut reader = BufReader::new(file);
     let mut content = String::new();
-    reader
-        .read_to_string(&mut content)
-        .expect("Could not read file");
+    reader.read_to_string(&mut content)?;
 
     let mut notes = Vec::new();
 
+    // tags for all notes of the file, e.g. `% alas-tags: limits, exam`
+    let file_tags: Vec<String> = FILE_TAGS_RE
+        .captures_iter(&content)
+        .flat_map(|cap| split_tags(&cap[1]))
+        .collect();
+
     let mut last_pos = 0;
 
     // iterate through each captured note
@@ -49,38 +373,85 @@ pub fn parse_tex_file(path: &PathBuf) -> Vec<Note> {
         let start = cap.get(0).unwrap().start();
         let end = cap.get(0).unwrap().end();
 
-        // extract the ID from the comment before the note
+        // extract the ID from the comment before the note; notes without an ID are skipped
         let before_note = &content[last_pos..start];
-        let id = ID_RE
-            .captures(before_note)
-            .expect("id should have been inserted")
-            .get(1)
-            .unwrap()
-            .as_str()
-            .to_string();
-
-        // capture note type and note body
-        let note_type = cap.get(1).map(|note_type| note_type.as_str().to_string());
-        let note_body = cap.get(2).map_or("", |m| m.as_str());
-
-        // extract fields from the note body
+        last_pos = end;
+        let id = match ID_RE.captures(before_note) {
+            Some(id_cap) => id_cap.get(1).unwrap().as_str().to_string(),
+            None => continue,
+        };
+
+        // capture note options and note body
+        let kind = match &cap[1] {
+            "cloze" => NoteKind::Cloze,
+            _ => NoteKind::Basic,
+        };
+        let options = cap.get(2).map_or_else(NoteOptions::default, |options| {
+            NoteOptions::parse(options.as_str())
+        });
+        let (note_body, body_start) = cap.get(3).map_or(("", end), |m| (m.as_str(), m.start()));
+
+        // extract fields from the note body together with the line they start in
         let mut fields = Vec::new();
+        let mut field_lines = Vec::new();
         for field_cap in FIELD_RE.captures_iter(note_body) {
-            let field_content = field_cap.get(1).map_or("", |m| m.as_str()).to_string();
-            fields.push(field_content);
+            let field = field_cap.get(1).unwrap();
+            fields.push(field.as_str().to_string());
+            field_lines.push(line_of(&content, body_start + field.start()));
         }
 
+        // the body of a cloze without field environments is its only field
+        if kind == NoteKind::Cloze && fields.is_empty() {
+            let text = note_body.trim();
+            let text_start = body_start + note_body.len() - note_body.trim_start().len();
+            fields.push(text.to_string());
+            field_lines.push(line_of(&content, text_start));
+        }
+
+        let mut tags: Vec<String> = options.tags.iter().chain(&file_tags).cloned().collect();
+        tags.sort();
+        tags.dedup();
+
         let note = Note {
             id,
-            note_type,
+            kind,
+            options,
+            html_fields: vec![None; fields.len()],
             fields,
+            field_lines,
+            file: path.clone(),
+            line: line_of(&content, start),
+            position: 0,
+            chapter: None,
+            section: None,
+            subdeck: Vec::new(),
+            tags,
         };
 
         notes.push(note);
-        last_pos = end;
     }
 
-    notes
+    Ok(notes)
+}
+
+fn line_of(content: &str, pos: usize) -> usize {
+    content[..pos].matches('\n').count() + 1
+}
+
+pub fn count_notes_without_id(path: &PathBuf) -> Result<usize, std::io::Error> {
+    let content = fs::read_to_string(path)?;
+
+    let mut count = 0;
+    let mut last_pos = 0;
+    for cap in NOTE_RE.captures_iter(&content) {
+        let m = cap.get(0).unwrap();
+        if !ID_RE.is_match(&content[last_pos..m.start()]) {
+            count += 1;
+        }
+        last_pos = m.end();
+    }
+
+    Ok(count)
 }
 
 pub fn insert_id_if_missing(path: &PathBuf) -> Result<(), std::io::Error> {
@@ -126,3 +497,92 @@ pub fn insert_id_if_missing(path: &PathBuf) -> Result<(), std::io::Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn options() {
+        assert_eq!(
+            NoteOptions::parse("definition, mathjax"),
+            NoteOptions {
+                note_type: Some("definition".to_string()),
+                output: Some(OutputMode::Mathjax),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            NoteOptions::parse("type=definition, cards=both, tags={limits, exam}"),
+            NoteOptions {
+                note_type: Some("definition".to_string()),
+                cards: Some(CardMode::Reverse),
+                tags: vec!["limits".to_string(), "exam".to_string()],
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            NoteOptions::parse("reverse, image, theorem, unknown=1"),
+            NoteOptions {
+                note_type: Some("theorem".to_string()),
+                output: Some(OutputMode::Image),
+                cards: Some(CardMode::Reverse),
+                ..Default::default()
+            }
+        );
+        assert_eq!(NoteOptions::parse(" , "), NoteOptions::default());
+    }
+
+    #[test]
+    fn tags() {
+        assert_eq!(split_tags("limits, exam"), ["limits", "exam"]);
+        assert_eq!(
+            split_tags(" limits exam::midterm,,"),
+            ["limits", "exam::midterm"]
+        );
+        assert!(split_tags(" ").is_empty());
+    }
+
+    #[test]
+    fn clozes() {
+        let text = r"a \cloze{1}{b} \cloze{ 2 }{c^{2}} \cloze{1}{$\frac{d}{e}$}";
+        assert_eq!(
+            cloze_latex(text, 1, false),
+            r"a \alasclozehidden{b} {c^{2}} \alasclozehidden{$\frac{d}{e}$}"
+        );
+        assert_eq!(
+            cloze_latex(text, 2, true),
+            r"a {b} \alasclozeshown{c^{2}} {$\frac{d}{e}$}"
+        );
+
+        // nested clozes and a missing closing brace
+        assert_eq!(
+            cloze_latex(r"\cloze{1}{x \cloze{2}{y}}", 2, false),
+            r"{x \alasclozehidden{y}}"
+        );
+        assert_eq!(cloze_latex(r"\cloze{1}{x", 1, true), r"\alasclozeshown{x}");
+    }
+
+    #[test]
+    fn cloze_ordinals() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.tex");
+        fs::write(
+            &path,
+            "% ID: a\n\\begin{cloze}\n  \\cloze{2}{x} \\cloze{1}{y} \\cloze{2}{z}\n\\end{cloze}\n",
+        )
+        .unwrap();
+
+        let notes = parse_tex_file(&path).unwrap();
+        assert_eq!(notes[0].kind, NoteKind::Cloze);
+        assert_eq!(notes[0].cloze_ordinals(), [1, 2]);
+        assert_eq!(notes[0].field_lines, [3]);
+
+        assert!(notes[0].check().is_ok());
+
+        fs::write(&path, "% ID: b\n\\begin{cloze}\n  text\n\\end{cloze}\n").unwrap();
+        let notes = parse_tex_file(&path).unwrap();
+        let err = notes[0].check().unwrap_err();
+        assert!(err.ends_with("notes.tex:3: cloze note b contains no \\cloze{n}{...}"));
+    }
+}
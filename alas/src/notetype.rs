@@ -0,0 +1,250 @@
+use serde::Deserialize;
+use std::{collections::HashMap, fs, path::Path};
+
+use rusqlite::Transaction;
+
+use anki_db::{Notetype as AnkiNotetype, NotetypeChange};
+
+use crate::config::Config;
+use crate::error::Error;
+use crate::messages::{print_message, MessageType};
+use crate::note::{CardMode, Note, NoteKind};
+use crate::theme;
+
+/// A note type defined in `.alas/notetypes/<name>/`: `notetype.json` lists the fields,
+/// `front.html` and `back.html` are the card templates and an optional `style.css` replaces the
+/// default styling.
+pub struct ProjectNotetype {
+    pub name: String,
+    fields: Vec<String>,
+    front: String,
+    back: String,
+    css: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NotetypeFile {
+    fields: Vec<String>,
+}
+
+impl ProjectNotetype {
+    pub fn load_all(alas_dir: &Path) -> Result<Vec<Self>, Error> {
+        let dir = alas_dir.join("notetypes");
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut entries = fs::read_dir(&dir)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort();
+
+        let mut notetypes = Vec::new();
+        for path in entries.into_iter().filter(|p| p.is_dir()) {
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            let read = |file: &str| {
+                fs::read_to_string(path.join(file)).map_err(|_| {
+                    Error::ConfigError(format!(
+                        "Note type '{}' is missing the file {}.",
+                        name, file
+                    ))
+                })
+            };
+
+            let file: NotetypeFile = serde_json::from_str(&read("notetype.json")?)
+                .map_err(|e| Error::ConfigError(format!("Note type '{}': {}", name, e)))?;
+            if file.fields.is_empty() {
+                return Err(Error::ConfigError(format!(
+                    "Note type '{}' has no fields.",
+                    name
+                )));
+            }
+
+            notetypes.push(ProjectNotetype {
+                fields: file.fields,
+                front: read("front.html")?,
+                back: read("back.html")?,
+                css: read("style.css").ok(),
+                name,
+            });
+        }
+        Ok(notetypes)
+    }
+
+    /// Returns the part of the definition the notes depend on: the fields they are written to.
+    pub fn fingerprint(&self) -> String {
+        self.fields.join("|")
+    }
+
+    /// Returns the notetype as it should be stored in anki.
+    pub fn build(&self, config: &Config) -> AnkiNotetype {
+        let mut notetype = AnkiNotetype::new(&format!("{}-{}", config.anki_identifier, self.name));
+        for field in &self.fields {
+            notetype = notetype.with_field(field);
+        }
+        notetype = notetype.with_template(
+            &format!("{}-{}-template", config.anki_identifier, self.name),
+            &self.front,
+            &self.back,
+            config.anki_deck_id.expect("deck is written to db"),
+        );
+        with_night_mode_css(match &self.css {
+            Some(css) => notetype.with_css(css),
+            None => notetype,
+        })
+    }
+}
+
+/// Returns the fingerprints of the note types defined in the project by their name.
+pub fn fingerprints(alas_dir: &Path) -> Result<HashMap<String, String>, Error> {
+    Ok(ProjectNotetype::load_all(alas_dir)?
+        .into_iter()
+        .map(|notetype| {
+            let fingerprint = notetype.fingerprint();
+            (notetype.name, fingerprint)
+        })
+        .collect())
+}
+
+/// The notetypes used by the notes of a project.
+pub struct Notetypes {
+    pub basic: AnkiNotetype,
+    pub cloze: Option<AnkiNotetype>,
+    /// notetypes defined in the project by their name
+    pub project: HashMap<String, AnkiNotetype>,
+}
+
+impl Notetypes {
+    /// Returns the project notetype named by the type option of the note, if there is one.
+    pub fn project_notetype(&self, note: &Note) -> Option<&AnkiNotetype> {
+        match note.kind {
+            NoteKind::Basic => self.project.get(note.options.note_type.as_deref()?),
+            NoteKind::Cloze => None,
+        }
+    }
+
+    /// Returns the notetype of the project with the id `ntid`.
+    pub fn by_id(&self, ntid: i64) -> Option<&AnkiNotetype> {
+        std::iter::once(&self.basic)
+            .chain(&self.cloze)
+            .chain(self.project.values())
+            .find(|notetype| notetype.get_id() == Some(ntid))
+    }
+
+    /// Returns the notetype new notes are added with.
+    pub fn of(&self, note: &Note) -> &AnkiNotetype {
+        match note.kind {
+            NoteKind::Cloze => self
+                .cloze
+                .as_ref()
+                .expect("cloze notetype is loaded for cloze notes"),
+            NoteKind::Basic => self.project_notetype(note).unwrap_or(&self.basic),
+        }
+    }
+}
+
+/// Returns the basic notetype as it should be stored in anki; the reverse template is only
+/// included once it is used, as adding it requires a full sync.
+pub fn basic_notetype(config: &Config, reverse: bool) -> Result<AnkiNotetype, Error> {
+    let deck_id = config.anki_deck_id.expect("deck is written to db");
+    let mut notetype = AnkiNotetype::new(&format!("{}-notetype", &config.anki_identifier))
+        .with_field("front")
+        .with_field("back")
+        .with_template(
+            &front_template_name(config),
+            include_str!("../templates/anki/minimal_front.txt"),
+            include_str!("../templates/anki/minimal_back.txt"),
+            deck_id,
+        );
+    if reverse {
+        notetype = notetype.with_template(
+            &reverse_template_name(config),
+            include_str!("../templates/anki/reverse_front.txt"),
+            include_str!("../templates/anki/reverse_back.txt"),
+            deck_id,
+        );
+    }
+    with_project_css(notetype, config)
+}
+
+fn front_template_name(config: &Config) -> String {
+    format!("{}-template", &config.anki_identifier)
+}
+
+pub fn reverse_template_name(config: &Config) -> String {
+    format!("{}-reverse-template", &config.anki_identifier)
+}
+
+/// Returns the ordinals of the templates of the basic notetype selected by `cards`; they are found
+/// by name, as templates added in anki may come before the reverse template.
+pub fn basic_template_ords(notetype: &AnkiNotetype, config: &Config, cards: CardMode) -> Vec<u16> {
+    let names = [front_template_name(config), reverse_template_name(config)];
+    cards
+        .template_idxs()
+        .iter()
+        .filter_map(|&idx| notetype.template_ord(&names[idx as usize]))
+        .collect()
+}
+
+/// Returns the cloze notetype as it should be stored in anki.
+pub fn cloze_notetype(config: &Config) -> Result<AnkiNotetype, Error> {
+    let notetype = AnkiNotetype::new(&format!("{}-cloze", &config.anki_identifier))
+        .with_cloze()
+        .with_field("text")
+        .with_field("back")
+        .with_template(
+            &format!("{}-cloze-template", &config.anki_identifier),
+            include_str!("../templates/anki/cloze_front.txt"),
+            include_str!("../templates/anki/cloze_back.txt"),
+            config.anki_deck_id.expect("deck is written to db"),
+        );
+    with_project_css(notetype, config)
+}
+
+/// Replaces the default styling of the built-in notetypes with `.alas/style.css` if it exists.
+fn with_project_css(notetype: AnkiNotetype, config: &Config) -> Result<AnkiNotetype, Error> {
+    let css_file = config.alas_dir().join("style.css");
+    if !css_file.exists() {
+        return Ok(with_night_mode_css(notetype));
+    }
+    Ok(with_night_mode_css(
+        notetype.with_css(&fs::read_to_string(css_file)?),
+    ))
+}
+
+/// Adds the rules choosing between the light and the night variant of themed images.
+fn with_night_mode_css(notetype: AnkiNotetype) -> AnkiNotetype {
+    let css = format!("{}{}", notetype.get_css(), theme::NIGHT_MODE_CSS);
+    notetype.with_css(&css)
+}
+
+/// Brings a notetype stored in anki in line with its definition, keeping the ids of its fields
+/// and templates. Fields can only be added after the existing ones.
+pub fn update_notetype(
+    notetype: &mut AnkiNotetype,
+    desired: &AnkiNotetype,
+    trans: &Transaction,
+) -> Result<(), Error> {
+    let change = notetype.update_from(desired);
+    if change == NotetypeChange::Incompatible {
+        return Err(Error::ConfigError(format!(
+            "Note type '{}' has the fields {} in Anki, which cannot be renamed, reordered or removed. Add new fields after them.",
+            notetype.get_name(),
+            notetype.field_names().join(", ")
+        )));
+    }
+    if change != NotetypeChange::Unchanged {
+        notetype.write_to_db(trans)?;
+    }
+    if change == NotetypeChange::SchemaChanged {
+        anki_db::set_schema_modified(trans)?;
+        print_message(
+            MessageType::Info,
+            &format!(
+                "Changed the fields or templates of note type '{}'. Anki will ask for a full sync.",
+                notetype.get_name()
+            ),
+        );
+    }
+    Ok(())
+}
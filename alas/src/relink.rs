@@ -0,0 +1,98 @@
+use regex::Regex;
+
+use anki_db::Note as AnkiNote;
+
+use crate::config::Config;
+use crate::renderer::ImageFormat;
+
+/// Returns the guid of the anki note of a note; it carries the note ID, so the note can be found
+/// again in a collection where it has another id.
+pub fn note_guid(config: &Config, note_id: &str) -> String {
+    format!("alas-{}-{}", config.anki_identifier, note_id)
+}
+
+/// Finds the note ID of an anki note by its guid or, for notes added before alas set the guid, by
+/// the names of its images.
+pub struct NoteKeys {
+    guid_prefix: String,
+    media_re: Regex,
+}
+
+impl NoteKeys {
+    pub fn new(config: &Config) -> Self {
+        let extensions = ImageFormat::ALL
+            .iter()
+            .map(|format| format.extension())
+            .collect::<Vec<_>>()
+            .join("|");
+
+        // image names end with the page, e.g. alas-<identifier>-<id>-0.svg, -0-c1q.svg for clozes
+        // or -0-night.svg for night variants
+        let media_re = Regex::new(&format!(
+            r#"alas-{}-([^"<>]+?)-\d+(?:-c\d+[qa])?(?:-night)?\.(?:{})"#,
+            regex::escape(&config.anki_identifier),
+            extensions
+        ))
+        .unwrap();
+
+        Self {
+            guid_prefix: note_guid(config, ""),
+            media_re,
+        }
+    }
+
+    pub fn note_id(&self, ankinote: &AnkiNote) -> Option<String> {
+        if let Some(note_id) = ankinote.get_guid().strip_prefix(&self.guid_prefix) {
+            return Some(note_id.to_string());
+        }
+
+        ankinote
+            .get_fields()
+            .iter()
+            .find_map(|field| self.media_re.captures(field))
+            .map(|cap| cap[1].to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn note_ids() {
+        let mut config = Config::default();
+        config.anki_identifier = "my-deck".to_string();
+        let keys = NoteKeys::new(&config);
+        let note = |guid: &str, field: &str| {
+            AnkiNote::new(1)
+                .with_guid(guid.to_string())
+                .with_fields(vec![field.to_string(), String::new()])
+        };
+
+        assert_eq!(
+            keys.note_id(&note(&note_guid(&config, "leg-1"), "")),
+            Some("leg-1".to_string())
+        );
+        assert_eq!(
+            keys.note_id(&note(
+                "abc",
+                r#"<img class="x" src="alas-my-deck-leg-1-0.svg">"#
+            )),
+            Some("leg-1".to_string())
+        );
+        assert_eq!(
+            keys.note_id(&note(
+                "abc",
+                r#"{{c1::<img src="alas-my-deck-d2-0-c1a-night.png">::x}}"#
+            )),
+            Some("d2".to_string())
+        );
+
+        // notes of other projects and without images
+        assert_eq!(keys.note_id(&note("alas-other-a", "text")), None);
+        assert_eq!(
+            keys.note_id(&note("abc", r#"<img src="alas-my-deck2-a-0.svg">"#)),
+            None
+        );
+    }
+}
@@ -0,0 +1,458 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::mpmc::{Receiver, Sender},
+};
+
+use crate::config::Config;
+use crate::error::Error;
+use crate::note::Note;
+use crate::renderer::{ImageFormat, Renderer};
+use crate::theme::{self, SvgTheme};
+
+const SKELETON: &str = include_str!("../templates/latex/skeleton.tex");
+
+/// Prefix of images which are not yet referenced by a note in the database.
+const STAGED_PREFIX: &str = ".alas-staged-";
+
+lazy_static! {
+    static ref LOG_LINE_RE: Regex = Regex::new(r"^l\.(\d+) ?(.*)$").unwrap();
+    static ref INPUT_RE: Regex = Regex::new(r"\\input\{([^}]+)\}").unwrap();
+}
+
+pub struct RenderContext {
+    pub renderer: Box<dyn Renderer>,
+    pub image_format: ImageFormat,
+    pub svg_theme: Option<SvgTheme>,
+    pub anki_media_dir: PathBuf,
+    pub identifier: String,
+    pub log_dir: PathBuf,
+    pub project_root: PathBuf,
+}
+
+pub enum RenderEvent {
+    Started(usize),
+    /// contains one result per note of the batch; the outer error aborts the sync
+    Finished(usize, Result<Vec<Result<(), RenderFailure>>, Error>),
+}
+
+pub struct RenderFailure {
+    pub message: String,
+    pub diagnostics: Vec<String>,
+}
+
+struct LatexError {
+    line: usize,
+    context: String,
+    message: String,
+}
+
+struct NoteError {
+    line: Option<usize>,
+    message: String,
+}
+
+/// Hashes the skeleton, the preamble files it includes and the render settings.
+pub fn fingerprint(config: &Config) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    hasher.update(SKELETON.as_bytes());
+    hasher.update(read_preamble().as_bytes());
+    hasher.update(
+        config
+            .renderer
+            .build(config.image_output())
+            .settings()
+            .as_bytes(),
+    );
+    hasher.update(format!("{:?}", config.output).as_bytes());
+    hasher.update(format!("{:?}", config.svg_theme()).as_bytes());
+    if config.svg_theme().is_some() {
+        hasher.update(theme::NIGHT_MODE_CSS.as_bytes());
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Returns the content of the preamble files included by the skeleton.
+pub fn read_preamble() -> String {
+    INPUT_RE
+        .captures_iter(SKELETON)
+        // missing preamble files are reported by latex itself
+        .filter_map(|cap| fs::read_to_string(cap.get(1).unwrap().as_str()).ok())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Renders batches received from `tasks` until the channel is closed.
+pub fn run_worker(
+    tasks: Receiver<(usize, Vec<Note>)>,
+    events: Sender<RenderEvent>,
+    context: &RenderContext,
+) {
+    for (batch_idx, notes) in tasks.iter() {
+        // stop if nobody is listening anymore
+        if events.send(RenderEvent::Started(batch_idx)).is_err() {
+            break;
+        }
+
+        let result = render_notes(&notes, context);
+
+        if events
+            .send(RenderEvent::Finished(batch_idx, result))
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+/// Renders the notes and bisects the batch on failure until the broken notes are isolated; a note
+/// which only fails outside of its fields aborts the sync.
+fn render_notes(
+    notes: &[Note],
+    context: &RenderContext,
+) -> Result<Vec<Result<(), RenderFailure>>, Error> {
+    // every attempt gets its own scratch directory, which is kept if a single note fails
+    let scratch_dir = tempfile::Builder::new().prefix("alas-").tempdir()?;
+
+    let result = generate_images_for_batch(notes, scratch_dir.path(), context)
+        .and_then(|_| move_images_to_anki_media(notes, scratch_dir.path(), context));
+
+    match result {
+        Ok(_) => Ok(notes.iter().map(|_| Ok(())).collect()),
+        Err(Error::JobError(message)) if notes.len() == 1 => {
+            let note = &notes[0];
+            let log = fs::read_to_string(scratch_dir.path().join("tmp.log")).unwrap_or_default();
+            let log_file = context.log_dir.join(format!("{}.log", note.id));
+            fs::create_dir_all(&context.log_dir)?;
+            fs::write(&log_file, &log)?;
+
+            // errors outside of the note, e.g. in the preamble, break every other note as well
+            let errors = locate_errors(note, &log);
+            if !errors.is_empty() && errors.iter().all(|err| err.line.is_none()) {
+                return Err(Error::LatexError(format!(
+                    "LaTeX fails outside of the notes, e.g. in the preamble: {}. See {}",
+                    errors[0].message,
+                    log_file.display()
+                )));
+            }
+
+            let mut diagnostics = diagnose_failure(note, &errors, &log_file);
+            diagnostics.push(format!(
+                "{}: files of note {} kept in {}",
+                note.file.display(),
+                note.id,
+                scratch_dir.into_path().display()
+            ));
+            Ok(vec![Err(RenderFailure {
+                message,
+                diagnostics,
+            })])
+        }
+        Err(Error::JobError(_)) => {
+            drop(scratch_dir);
+            let (left, right) = notes.split_at(notes.len() / 2);
+            let mut results = render_notes(left, context)?;
+            results.extend(render_notes(right, context)?);
+            Ok(results)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Returns the latex document and the line in which each page starts.
+fn compose_latex(batch: &[Note]) -> (String, Vec<usize>) {
+    let (head, tail) = SKELETON
+        .split_once("{{content}}")
+        .expect("skeleton contains content placeholder");
+
+    let mut latex = head.to_string();
+    let mut field_starts = Vec::new();
+
+    // TODO can one field exceed one page?
+    for page in batch.iter().flat_map(|note| note.pages()) {
+        latex.push_str(" \n\\newpage ");
+        field_starts.push(latex.matches('\n').count() + 1);
+        latex.push_str(&page.latex.replace("\\newpage", ""));
+    }
+
+    latex.push_str(tail);
+    (latex, field_starts)
+}
+
+fn generate_images_for_batch(
+    batch: &[Note],
+    scratch_dir: &Path,
+    context: &RenderContext,
+) -> Result<(), Error> {
+    // fields shown with MathJax need no rendering
+    let num_pages: usize = batch.iter().map(|note| note.pages().len()).sum();
+    if num_pages == 0 {
+        return Ok(());
+    }
+
+    let (latex, _) = compose_latex(batch);
+
+    let tex_file = scratch_dir.join("tmp.tex");
+    fs::write(&tex_file, latex)?;
+
+    context
+        .renderer
+        .render(&tex_file, scratch_dir, &context.project_root)?;
+
+    // assume rendering was successfull if there is one file per page
+    let last_file = scratch_dir.join(format!(
+        "tmp-{:03}.{}",
+        num_pages,
+        context.image_format.extension()
+    ));
+    if !last_file.exists() {
+        return Err(Error::JobError("failed rendering".to_string()));
+    }
+
+    Ok(())
+}
+
+/// Stages the images of the notes in the media folder; they are moved into place once the notes
+/// are written to the database.
+fn move_images_to_anki_media(
+    notes: &[Note],
+    scratch_dir: &Path,
+    context: &RenderContext,
+) -> Result<(), Error> {
+    let extension = context.image_format.extension();
+
+    let mut i = 1;
+    for note in notes {
+        for page in note.pages() {
+            let src = scratch_dir.join(format!("tmp-{:03}.{}", i, extension)); // TODO batch larger than 99?
+            fs::copy(&src, staged_path(note, &page.name, context))?;
+            if let Some(theme) = &context.svg_theme {
+                fs::write(
+                    staged_path(note, &theme::night_page(&page.name), context),
+                    theme.night(&fs::read_to_string(&src)?),
+                )?;
+            }
+            fs::remove_file(&src)?;
+            i += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Moves the staged images of a note written to the database into place.
+pub fn publish_media(note: &Note, context: &RenderContext) -> Result<(), Error> {
+    for page in media_pages(note, context) {
+        let staged = staged_path(note, &page, context);
+        if staged.exists() {
+            let dest = context.anki_media_dir.join(media_file_name(
+                note,
+                &page,
+                context.image_format,
+                context,
+            ));
+            fs::rename(staged, dest)?;
+        }
+    }
+    remove_stale_media(note, context)
+}
+
+/// Removes the staged images of a note which was not written to the database.
+pub fn discard_media(note: &Note, context: &RenderContext) -> Result<(), Error> {
+    for page in media_pages(note, context) {
+        let staged = staged_path(note, &page, context);
+        if staged.exists() {
+            fs::remove_file(staged)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes images staged by an interrupted sync.
+pub fn remove_staged_media(context: &RenderContext) -> Result<(), Error> {
+    if !context.anki_media_dir.exists() {
+        return Ok(());
+    }
+
+    let prefix = format!("{}alas-{}-", STAGED_PREFIX, context.identifier);
+    for entry in fs::read_dir(&context.anki_media_dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with(&prefix) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the pages of all images of the note, including the night variants.
+fn media_pages(note: &Note, context: &RenderContext) -> Vec<String> {
+    let mut pages = Vec::new();
+    for page in note.pages() {
+        if context.svg_theme.is_some() {
+            pages.push(theme::night_page(&page.name));
+        }
+        pages.push(page.name);
+    }
+    pages
+}
+
+fn staged_path(note: &Note, page: &str, context: &RenderContext) -> PathBuf {
+    context.anki_media_dir.join(format!(
+        "{}{}",
+        STAGED_PREFIX,
+        media_file_name(note, page, context.image_format, context)
+    ))
+}
+
+fn media_file_name(
+    note: &Note,
+    page: &str,
+    format: ImageFormat,
+    context: &RenderContext,
+) -> String {
+    format!(
+        "alas-{}-{}-{}.{}",
+        context.identifier,
+        note.id,
+        page,
+        format.extension()
+    )
+}
+
+/// Removes images left over from another image format, from fields now shown with MathJax or
+/// night variants of images which are no longer themed.
+fn remove_stale_media(note: &Note, context: &RenderContext) -> Result<(), Error> {
+    let other_formats = note.pages().into_iter().flat_map(|page| {
+        ImageFormat::ALL
+            .into_iter()
+            .filter(|format| *format != context.image_format)
+            .map(move |format| (page.name.clone(), format))
+    });
+    let html_fields = note
+        .html_fields
+        .iter()
+        .enumerate()
+        .filter(|(_, html)| html.is_some())
+        .flat_map(|(field, _)| {
+            ImageFormat::ALL
+                .into_iter()
+                .map(move |format| (field.to_string(), format))
+        });
+    let stale = other_formats.chain(html_fields).collect::<Vec<_>>();
+
+    // night variants go with their images
+    let night = stale
+        .iter()
+        .map(|(page, format)| (theme::night_page(page), *format))
+        .collect::<Vec<_>>();
+    let unthemed = note
+        .pages()
+        .into_iter()
+        .filter(|_| context.svg_theme.is_none())
+        .map(|page| (theme::night_page(&page.name), context.image_format));
+
+    for (page, format) in stale.into_iter().chain(night).chain(unthemed) {
+        let file = context
+            .anki_media_dir
+            .join(media_file_name(note, &page, format, context));
+        if file.exists() {
+            fs::remove_file(file)?;
+        }
+    }
+    Ok(())
+}
+
+/// Maps the errors in the latex log of a single note to lines of its source file; errors outside of
+/// its fields have no line.
+fn locate_errors(note: &Note, log: &str) -> Vec<NoteError> {
+    let (latex, field_starts) = compose_latex(std::slice::from_ref(note));
+    let latex_lines: Vec<&str> = latex.lines().collect();
+    let page_fields: Vec<usize> = note.pages().iter().map(|page| page.field).collect();
+
+    parse_latex_log(log)
+        .into_iter()
+        .map(|err| {
+            // errors in the preamble or in included files refer to other line numbers
+            let in_content = err
+                .line
+                .checked_sub(1)
+                .and_then(|i| latex_lines.get(i))
+                .is_some_and(|line| line.contains(err.context.trim().trim_start_matches("...")));
+
+            let line = match field_starts.iter().rposition(|start| err.line >= *start) {
+                Some(page) if in_content => {
+                    Some(note.field_lines[page_fields[page]] + err.line - field_starts[page])
+                }
+                _ => None,
+            };
+            NoteError {
+                line,
+                message: err.message,
+            }
+        })
+        .collect()
+}
+
+/// Describes the errors of a failed note, with the log file if latex reported none.
+fn diagnose_failure(note: &Note, errors: &[NoteError], log_file: &Path) -> Vec<String> {
+    let mut diagnostics: Vec<String> = errors
+        .iter()
+        .map(|err| match err.line {
+            Some(line) => format!("{}:{}: {}", note.file.display(), line, err.message),
+            None => format!(
+                "{}: {} (in preamble or included file)",
+                note.file.display(),
+                err.message
+            ),
+        })
+        .collect();
+
+    if diagnostics.is_empty() {
+        diagnostics.push(format!(
+            "{}: failed rendering note {}, see {}",
+            note.file.display(),
+            note.id,
+            log_file.display()
+        ));
+    }
+
+    diagnostics
+}
+
+fn parse_latex_log(log: &str) -> Vec<LatexError> {
+    let mut errors = Vec::new();
+    let mut lines = log.lines();
+
+    while let Some(line) = lines.next() {
+        let message = match line.strip_prefix("! ") {
+            Some(message) => message.trim_end_matches('.').to_string(),
+            None => continue,
+        };
+
+        // the line number follows within the next lines of the error message
+        for next in lines.by_ref().take(10) {
+            if let Some(cap) = LOG_LINE_RE.captures(next) {
+                let context = cap.get(2).unwrap().as_str().to_string();
+
+                // name the offending macro for undefined control sequences
+                let message = match context.split_whitespace().last() {
+                    Some(token) if message == "Undefined control sequence" => {
+                        format!("{} {}", message, token)
+                    }
+                    _ => message,
+                };
+
+                errors.push(LatexError {
+                    line: cap.get(1).unwrap().as_str().parse().unwrap(),
+                    context,
+                    message,
+                });
+                break;
+            }
+        }
+    }
+
+    errors
+}
@@ -0,0 +1,264 @@
+use serde::{Deserialize, Serialize};
+use std::{path::Path, process::Command};
+
+use crate::error::Error;
+
+const DVISVGM_ARGS: [&str; 3] = ["--no-fonts", "-Z", "2"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum RendererKind {
+    #[default]
+    #[serde(rename = "latex+dvisvgm")]
+    LatexDvisvgm,
+    #[serde(rename = "pdflatex")]
+    Pdflatex,
+    #[serde(rename = "lualatex")]
+    Lualatex,
+    #[serde(rename = "xelatex")]
+    Xelatex,
+    #[serde(rename = "tectonic")]
+    Tectonic,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    #[default]
+    Svg,
+    Png,
+}
+
+impl ImageFormat {
+    pub const ALL: [ImageFormat; 2] = [ImageFormat::Svg, ImageFormat::Png];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Svg => "svg",
+            ImageFormat::Png => "png",
+        }
+    }
+}
+
+/// Converts the dvi or pdf output of latex into one image per page.
+#[derive(Debug, Clone, Copy)]
+pub struct ImageOutput {
+    pub format: ImageFormat,
+    pub dpi: u32,
+}
+
+impl ImageOutput {
+    fn required_programs(&self, pdf: bool) -> Vec<&'static str> {
+        match (self.format, pdf) {
+            (ImageFormat::Svg, _) => vec!["dvisvgm"],
+            (ImageFormat::Png, false) => vec!["dvipng"],
+            (ImageFormat::Png, true) => vec!["pdfcrop", "gs"],
+        }
+    }
+
+    fn convert(&self, input: &Path, scratch_dir: &Path, pdf: bool) -> Result<(), Error> {
+        match (self.format, pdf) {
+            (ImageFormat::Svg, _) => run_dvisvgm(input, scratch_dir, pdf),
+            (ImageFormat::Png, false) => run_dvipng(input, scratch_dir, self.dpi),
+            (ImageFormat::Png, true) => run_ghostscript(input, scratch_dir, self.dpi),
+        }
+    }
+
+    fn settings(&self, pdf: bool) -> String {
+        match (self.format, pdf) {
+            (ImageFormat::Svg, false) => format!("dvisvgm {}", DVISVGM_ARGS.join(" ")),
+            (ImageFormat::Svg, true) => format!("dvisvgm --pdf {}", DVISVGM_ARGS.join(" ")),
+            (ImageFormat::Png, false) => format!("dvipng {}dpi", self.dpi),
+            (ImageFormat::Png, true) => format!("pdfcrop gs {}dpi", self.dpi),
+        }
+    }
+}
+
+impl RendererKind {
+    pub fn build(&self, output: ImageOutput) -> Box<dyn Renderer> {
+        match self {
+            RendererKind::LatexDvisvgm => Box::new(Latex(output)),
+            RendererKind::Pdflatex => Box::new(PdfEngine("pdflatex", output)),
+            RendererKind::Lualatex => Box::new(PdfEngine("lualatex", output)),
+            RendererKind::Xelatex => Box::new(PdfEngine("xelatex", output)),
+            RendererKind::Tectonic => Box::new(Tectonic(output)),
+        }
+    }
+}
+
+/// A latex backend turning `tmp.tex` into one `tmp-NNN.svg` or `tmp-NNN.png` file per page.
+pub trait Renderer: Send + Sync {
+    fn required_programs(&self) -> Vec<&'static str>;
+
+    /// Renders `tex_file` into `scratch_dir`; \input paths are resolved relative to `project_root`,
+    /// which is the working directory of latex.
+    fn render(&self, tex_file: &Path, scratch_dir: &Path, project_root: &Path)
+        -> Result<(), Error>;
+
+    /// Describes everything affecting the output, used to detect changed render settings.
+    fn settings(&self) -> String;
+}
+
+/// latex followed by dvisvgm or dvipng
+struct Latex(ImageOutput);
+
+impl Renderer for Latex {
+    fn required_programs(&self) -> Vec<&'static str> {
+        [vec!["latex"], self.0.required_programs(false)].concat()
+    }
+
+    fn render(
+        &self,
+        tex_file: &Path,
+        scratch_dir: &Path,
+        project_root: &Path,
+    ) -> Result<(), Error> {
+        // paths in the preamble and in notes are resolved as when compiling the document
+        let output = Command::new("latex")
+            .current_dir(project_root)
+            .arg("-interaction=nonstopmode")
+            .arg(format!("-output-directory={}", scratch_dir.display()))
+            .arg(tex_file)
+            .output()?;
+
+        // latex recovers from most errors in nonstopmode but still reports them
+        if !output.status.success() {
+            return Err(Error::JobError("failed rendering".to_string()));
+        }
+
+        self.0
+            .convert(&scratch_dir.join("tmp.dvi"), scratch_dir, false)
+    }
+
+    fn settings(&self) -> String {
+        format!("latex {}", self.0.settings(false))
+    }
+}
+
+/// pdflatex, lualatex or xelatex followed by a pdf converter
+struct PdfEngine(&'static str, ImageOutput);
+
+impl Renderer for PdfEngine {
+    fn required_programs(&self) -> Vec<&'static str> {
+        [vec![self.0], self.1.required_programs(true)].concat()
+    }
+
+    fn render(
+        &self,
+        tex_file: &Path,
+        scratch_dir: &Path,
+        project_root: &Path,
+    ) -> Result<(), Error> {
+        let output = Command::new(self.0)
+            .current_dir(project_root)
+            .arg("-interaction=nonstopmode")
+            .arg(format!("-output-directory={}", scratch_dir.display()))
+            .arg(tex_file)
+            .output()?;
+
+        if !output.status.success() {
+            return Err(Error::JobError("failed rendering".to_string()));
+        }
+
+        self.1
+            .convert(&scratch_dir.join("tmp.pdf"), scratch_dir, true)
+    }
+
+    fn settings(&self) -> String {
+        format!("{} {}", self.0, self.1.settings(true))
+    }
+}
+
+struct Tectonic(ImageOutput);
+
+impl Renderer for Tectonic {
+    fn required_programs(&self) -> Vec<&'static str> {
+        [vec!["tectonic"], self.0.required_programs(true)].concat()
+    }
+
+    fn render(
+        &self,
+        tex_file: &Path,
+        scratch_dir: &Path,
+        project_root: &Path,
+    ) -> Result<(), Error> {
+        // tectonic resolves files relative to the input file and has its own search path option
+        let output = Command::new("tectonic")
+            .current_dir(project_root)
+            .arg("--keep-logs")
+            .arg("-Z")
+            .arg(format!("search-path={}", project_root.display()))
+            .arg("--outdir")
+            .arg(scratch_dir)
+            .arg(tex_file)
+            .output()?;
+
+        if !output.status.success() {
+            return Err(Error::JobError("failed rendering".to_string()));
+        }
+
+        self.0
+            .convert(&scratch_dir.join("tmp.pdf"), scratch_dir, true)
+    }
+
+    fn settings(&self) -> String {
+        format!("tectonic {}", self.0.settings(true))
+    }
+}
+
+fn run_dvisvgm(input: &Path, scratch_dir: &Path, pdf: bool) -> Result<(), Error> {
+    let mut command = Command::new("dvisvgm");
+    if pdf {
+        command.arg("--pdf");
+    }
+
+    // generate one svg file for each page
+    command
+        .args(DVISVGM_ARGS)
+        .arg(input)
+        .arg("--page=1-")
+        .arg("-o")
+        .arg(scratch_dir.join("tmp-%3p.svg"))
+        .output()?;
+
+    Ok(())
+}
+
+fn run_dvipng(input: &Path, scratch_dir: &Path, dpi: u32) -> Result<(), Error> {
+    // generate one png file for each page, cropped to its content
+    Command::new("dvipng")
+        .args(["-T", "tight", "-bg", "Transparent", "-D"])
+        .arg(dpi.to_string())
+        .arg("-o")
+        .arg(scratch_dir.join("tmp-%03d.png"))
+        .arg(input)
+        .output()?;
+
+    Ok(())
+}
+
+fn run_ghostscript(input: &Path, scratch_dir: &Path, dpi: u32) -> Result<(), Error> {
+    // pdf pages have the full paper size, so they are cropped to their content first
+    let cropped = scratch_dir.join("tmp-crop.pdf");
+    let output = Command::new("pdfcrop").arg(input).arg(&cropped).output()?;
+    if !output.status.success() {
+        return Err(Error::JobError("failed cropping pdf".to_string()));
+    }
+
+    Command::new("gs")
+        .args([
+            "-dSAFER",
+            "-dBATCH",
+            "-dNOPAUSE",
+            "-dQUIET",
+            "-sDEVICE=pngalpha",
+        ])
+        .arg(format!("-r{}", dpi))
+        .arg(format!(
+            "-sOutputFile={}",
+            scratch_dir.join("tmp-%03d.png").display()
+        ))
+        .arg(&cropped)
+        .output()?;
+
+    Ok(())
+}
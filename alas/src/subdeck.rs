@@ -0,0 +1,264 @@
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+use crate::error::Error;
+use crate::note::Note;
+
+lazy_static! {
+    // matches up to the opening brace of the title, which may contain braces itself
+    static ref HEADING_RE: Regex =
+        Regex::new(r"(?m)^[^%\n]*?\\(chapter|section)(\*?)\s*(?:\[([^\]]*)\])?\s*\{").unwrap();
+    static ref COMMAND_RE: Regex = Regex::new(r"\\(?:[A-Za-z]+\*?|(.))").unwrap();
+}
+
+/// Which part of the document structure becomes a subdeck of the project deck.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SubdeckMode {
+    #[default]
+    None,
+    File,
+    Chapter,
+    Section,
+}
+
+/// Keeps track of the chapter and section numbers across the files of the document.
+#[derive(Default)]
+pub struct Outline {
+    chapter: usize,
+    section: usize,
+}
+
+impl Outline {
+    /// Sets the chapter, section and subdeck of each note in `file`; notes before the first heading
+    /// stay in the parent deck.
+    pub fn assign_subdecks(
+        &mut self,
+        mode: SubdeckMode,
+        file: &Path,
+        notes: &mut [Note],
+    ) -> Result<(), Error> {
+        // headings are counted in every mode so that the numbers do not depend on it
+        let content = fs::read_to_string(file)?;
+        let mut chapter = None;
+        let mut section = None;
+        let mut notes = notes.iter_mut().peekable();
+
+        for cap in HEADING_RE.captures_iter(&content) {
+            let m = cap.get(0).unwrap();
+            let Some(title) = braced(&content[m.end()..]) else {
+                continue;
+            };
+            let line = content[..m.start()].matches('\n').count() + 1;
+            while let Some(note) = notes.next_if(|note| note.line < line) {
+                place(note, mode, file, &chapter, &section);
+            }
+
+            // the short title is meant for places with little space like a deck list
+            let starred = !cap[2].is_empty();
+            let title = match cap
+                .get(3)
+                .map(|short| deck_name(&plain_text(short.as_str())))
+            {
+                Some(short) if !short.is_empty() => short,
+                _ => deck_name(&plain_text(title)),
+            };
+            if &cap[1] == "chapter" {
+                if !starred {
+                    self.chapter += 1;
+                    self.section = 0;
+                }
+                chapter = Some(match starred {
+                    true => title,
+                    false => format!("Chapter {} {}", self.chapter, title),
+                });
+                section = None;
+            } else {
+                if !starred {
+                    self.section += 1;
+                }
+                section = Some(match (starred, self.chapter) {
+                    (true, _) => title,
+                    (false, 0) => format!("Section {} {}", self.section, title),
+                    (false, c) => format!("Section {}.{} {}", c, self.section, title),
+                });
+            }
+        }
+
+        for note in notes {
+            place(note, mode, file, &chapter, &section);
+        }
+        Ok(())
+    }
+}
+
+fn place(
+    note: &mut Note,
+    mode: SubdeckMode,
+    file: &Path,
+    chapter: &Option<String>,
+    section: &Option<String>,
+) {
+    note.chapter = chapter.clone();
+    note.section = section.clone();
+    note.subdeck = match mode {
+        SubdeckMode::File => vec![deck_name(&file.with_extension("").display().to_string())],
+        SubdeckMode::Chapter => chapter.iter().cloned().collect(),
+        SubdeckMode::Section => chapter.iter().chain(section).cloned().collect(),
+        SubdeckMode::None => Vec::new(),
+    };
+}
+
+/// Returns the text up to the brace closing an already opened group.
+fn braced(text: &str) -> Option<&str> {
+    let mut depth = 1;
+    for (i, c) in text.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            _ => (),
+        }
+        if depth == 0 {
+            return Some(&text[..i]);
+        }
+    }
+    None
+}
+
+/// Drops commands, braces and math shifts from a title, e.g. `Limits of {\em real} sequences`;
+/// escaped characters like `\&` are kept.
+fn plain_text(latex: &str) -> String {
+    COMMAND_RE
+        .replace_all(latex, |cap: &Captures| match cap.get(1) {
+            Some(c) if c.as_str() != "\\" => c.as_str().to_string(),
+            _ => " ".to_string(),
+        })
+        .chars()
+        .filter(|c| !matches!(c, '{' | '}' | '$'))
+        .map(|c| if c == '~' { ' ' } else { c })
+        .collect()
+}
+
+/// Collapses whitespace; `::` would start another level of the deck hierarchy in anki.
+fn deck_name(title: &str) -> String {
+    title
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace("::", ":")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::note;
+
+    fn assign(outline: &mut Outline, mode: SubdeckMode, content: &str) -> Vec<Note> {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lec.tex");
+        fs::write(&file, content).unwrap();
+        let mut notes = note::parse_tex_file(&file).unwrap();
+        outline.assign_subdecks(mode, &file, &mut notes).unwrap();
+        notes
+    }
+
+    fn note(id: &str) -> String {
+        format!(
+            "% ID: {}\n\\begin{{note}}\\begin{{field}}x\\end{{field}}\\end{{note}}\n",
+            id
+        )
+    }
+
+    fn subdecks(notes: &[Note]) -> Vec<String> {
+        notes.iter().map(|n| n.subdeck.join("::")).collect()
+    }
+
+    #[test]
+    fn sections() {
+        let content = [
+            note("intro"),
+            "\\chapter{Sequences}\n".to_string(),
+            note("a"),
+            "\\section{Limits :: of sequences}\n".to_string(),
+            note("b"),
+            "% \\section{Commented}\n\\section*{Exercises}\n".to_string(),
+            note("c"),
+        ]
+        .concat();
+
+        let notes = assign(&mut Outline::default(), SubdeckMode::Section, &content);
+        assert_eq!(
+            subdecks(&notes),
+            [
+                "",
+                "Chapter 1 Sequences",
+                "Chapter 1 Sequences::Section 1.1 Limits : of sequences",
+                "Chapter 1 Sequences::Exercises",
+            ]
+        );
+        assert_eq!(notes[2].chapter.as_deref(), Some("Chapter 1 Sequences"));
+        assert_eq!(
+            notes[2].section.as_deref(),
+            Some("Section 1.1 Limits : of sequences")
+        );
+
+        let notes = assign(&mut Outline::default(), SubdeckMode::Chapter, &content);
+        assert_eq!(
+            subdecks(&notes),
+            [
+                "",
+                "Chapter 1 Sequences",
+                "Chapter 1 Sequences",
+                "Chapter 1 Sequences"
+            ]
+        );
+    }
+
+    #[test]
+    fn numbering_across_files() {
+        let mut outline = Outline::default();
+        assign(
+            &mut outline,
+            SubdeckMode::None,
+            "\\section{One}\n\\section{Two}\n",
+        );
+        let notes = assign(
+            &mut outline,
+            SubdeckMode::Section,
+            &format!("\\section{{Three\n  part}}\n{}", note("a")),
+        );
+        assert_eq!(subdecks(&notes), ["Section 3 Three part"]);
+    }
+
+    #[test]
+    fn titles() {
+        let content = [
+            "\\section[Limits]{Limits of {\\em real} sequences}\n".to_string(),
+            note("a"),
+            "\\section{Limits of {\\em real} sequences in $\\mathbb{R}$}\n".to_string(),
+            note("b"),
+            "\\section[ ]{Sums \\& series~I}\n".to_string(),
+            note("c"),
+        ]
+        .concat();
+
+        let notes = assign(&mut Outline::default(), SubdeckMode::Section, &content);
+        assert_eq!(
+            subdecks(&notes),
+            [
+                "Section 1 Limits",
+                "Section 2 Limits of real sequences in R",
+                "Section 3 Sums & series I",
+            ]
+        );
+    }
+
+    #[test]
+    fn file_subdecks() {
+        let notes = assign(&mut Outline::default(), SubdeckMode::File, &note("a"));
+        assert!(notes[0].subdeck[0].ends_with("lec"));
+        assert!(!notes[0].subdeck[0].contains("::"));
+    }
+}
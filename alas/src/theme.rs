@@ -0,0 +1,115 @@
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use std::collections::BTreeMap;
+
+lazy_static! {
+    static ref COLOR_ATTR_RE: Regex = Regex::new(r#"\b(fill|stroke)=(['"])([^'"]*)['"]"#).unwrap();
+    static ref COLOR_PROP_RE: Regex =
+        Regex::new(r"\b(fill|stroke)\s*:\s*(#[0-9a-fA-F]{3,6}|black)\b").unwrap();
+    static ref SVG_TAG_RE: Regex = Regex::new(r"<svg\b[^>]*>").unwrap();
+}
+
+/// Rewrites svg colors for cards shown in night mode; the light images are kept as rendered.
+#[derive(Debug, Clone)]
+pub struct SvgTheme {
+    /// user colors and the colors used instead in night mode
+    pub colors: BTreeMap<String, String>,
+}
+
+/// Text color of images in night mode.
+const NIGHT_TEXT: &str = "#ffffff";
+
+/// Shows the light or the night variant of themed images depending on anki's night mode; the rules
+/// are added to every notetype, as the styling of a notetype can be replaced.
+pub const NIGHT_MODE_CSS: &str = "
+img.alas-night {
+    display: none;
+}
+
+.night_mode img.alas-day {
+    display: none;
+}
+
+.night_mode img.alas-night {
+    display: inline;
+}
+";
+
+impl SvgTheme {
+    /// Returns the night variant of the svg: black becomes the night text color and user colors are
+    /// replaced by their night colors.
+    pub fn night(&self, svg: &str) -> String {
+        let colors: BTreeMap<String, String> = self
+            .colors
+            .iter()
+            .map(|(light, dark)| (normalize_color(light), dark.clone()))
+            .collect();
+        let night_color = |color: &str| match normalize_color(color).as_str() {
+            "#000000" => Some(NIGHT_TEXT.to_string()),
+            color => colors.get(color).cloned(),
+        };
+
+        let svg = COLOR_ATTR_RE.replace_all(svg, |cap: &Captures| match night_color(&cap[3]) {
+            Some(color) => format!("{}={}{}{}", &cap[1], &cap[2], color, &cap[2]),
+            None => cap[0].to_string(),
+        });
+        let svg = COLOR_PROP_RE.replace_all(&svg, |cap: &Captures| match night_color(&cap[2]) {
+            Some(color) => format!("{}:{}", &cap[1], color),
+            None => cap[0].to_string(),
+        });
+
+        // shapes without a fill are drawn in black
+        let style = format!("<style>svg{{fill:{}}}</style>", NIGHT_TEXT);
+        match SVG_TAG_RE.find(&svg) {
+            Some(tag) => format!("{}{}{}", &svg[..tag.end()], style, &svg[tag.end()..]),
+            None => svg.into_owned(),
+        }
+    }
+}
+
+/// Returns the name of the page of the night variant of an image.
+pub fn night_page(page: &str) -> String {
+    format!("{}-night", page)
+}
+
+/// Returns colors as lowercase six digit hex codes, e.g. `#F00` becomes `#ff0000`.
+fn normalize_color(color: &str) -> String {
+    let color = color.trim().to_lowercase();
+    match color.as_str() {
+        "black" => "#000000".to_string(),
+        c if c.len() == 4 && c.starts_with('#') => {
+            let mut expanded = String::from("#");
+            for digit in c[1..].chars() {
+                expanded.push(digit);
+                expanded.push(digit);
+            }
+            expanded
+        }
+        _ => color,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn night_variant() {
+        let theme = SvgTheme {
+            colors: BTreeMap::from([("#F00".to_string(), "#ff8080".to_string())]),
+        };
+        let svg = r##"<svg width="10"><path fill="black" stroke='#000'/><g style="fill:#ff0000;stroke: #00f"/></svg>"##;
+        assert_eq!(
+            theme.night(svg),
+            r##"<svg width="10"><style>svg{fill:#ffffff}</style><path fill="#ffffff" stroke='#ffffff'/><g style="fill:#ff8080;stroke: #00f"/></svg>"##
+        );
+    }
+
+    #[test]
+    fn colors() {
+        assert_eq!(normalize_color(" Black"), "#000000");
+        assert_eq!(normalize_color("#aBc"), "#aabbcc");
+        assert_eq!(normalize_color("#123456"), "#123456");
+        assert_eq!(night_page("0-c1q"), "0-c1q-night");
+    }
+}
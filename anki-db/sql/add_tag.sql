@@ -0,0 +1,3 @@
+INSERT
+  OR IGNORE INTO tags (tag, usn, collapsed, config)
+VALUES (?, -1, 0, NULL)
@@ -0,0 +1,6 @@
+UPDATE deck_config
+SET name = ?,
+  mtime_secs = ?,
+  usn = ?,
+  config = ?
+WHERE id = ?
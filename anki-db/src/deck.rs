@@ -2,7 +2,7 @@
 // You can find the original code at https://github.com/ankitects/anki.
 
 use prost::Message;
-use rusqlite::{params, Transaction};
+use rusqlite::{params, OptionalExtension, Transaction};
 
 pub use anki_proto::decks::deck::{
     kind_container::Kind as KindProto, Common as CommonProto, KindContainer as KindContainerProto,
@@ -61,7 +61,10 @@ impl AnkiDeck {
 
         let config_id = match &kind.kind {
             Some(KindProto::Normal(normal)) => normal.config_id,
-            _ => panic!("wrong kind type"),
+            _ => {
+                let name: String = row.get(1)?;
+                return Err(Error::FilteredDeck(name.replace('\x1f', "::")));
+            }
         };
 
         let conf =
@@ -78,9 +81,51 @@ impl AnkiDeck {
         }))
     }
 
+    pub fn load_by_name(name: &str, trans: &Transaction) -> Result<Option<Self>, Error> {
+        let did: Option<i64> = trans
+            .prepare_cached("SELECT id FROM decks WHERE name = ?")?
+            .query_row(params![name], |row| row.get(0))
+            .optional()?;
+
+        match did {
+            Some(did) => Self::load(did, trans),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the names of the decks using the deck config `dcid`.
+    pub fn names_using_config(dcid: i64, trans: &Transaction) -> Result<Vec<String>, Error> {
+        let mut stmt = trans.prepare_cached("SELECT name, kind FROM decks")?;
+        let mut rows = stmt.query([])?;
+
+        let mut names = Vec::new();
+        while let Some(row) = rows.next()? {
+            let kind = KindContainerProto::decode(row.get_ref_unwrap(1).as_blob()?)?;
+            if matches!(kind.kind, Some(KindProto::Normal(normal)) if normal.config_id == dcid) {
+                names.push(row.get(0)?);
+            }
+        }
+        Ok(names)
+    }
+
+    /// Creates a subdeck which shares the deck config of this deck.
+    pub fn new_child(&self, name: &str) -> Self {
+        Self {
+            did: None,
+            name: format!("{}\x1f{}", self.name, name),
+            mtime: 0,
+            usn: -1,
+            common: self.common.clone(),
+            kind: None,
+            conf: self.conf.clone(),
+        }
+    }
+
     pub fn write_to_db(&mut self, trans: &Transaction) -> Result<i64, Error> {
-        // write deckconfig to db
-        self.conf.write_to_db(trans)?;
+        // write deckconfig to db unless it is shared with an existing deck
+        if self.conf.get_id().is_none() {
+            self.conf.write_to_db(trans)?;
+        }
 
         self.kind = Some(KindProto::Normal(NormalProto {
             config_id: self.conf.get_id().expect("deckconfig not written to db"),
@@ -3,6 +3,7 @@
 
 use prost::Message;
 use rusqlite::{params, Transaction};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub use anki_proto::deck_config::deck_config::{
     config::{
@@ -89,13 +90,26 @@ impl DeckConfig {
         let mut conf_bytes = vec![];
         self.inner.encode(&mut conf_bytes)?;
 
-        trans
-            .prepare_cached(include_str!("../sql/add_deckconfig.sql"))?
-            .execute(params![
-                self.dcid, self.name, self.mtime, self.usn, conf_bytes,
-            ])?;
+        if let Some(dcid) = self.dcid {
+            // update deckconfig, anki syncs deckconfigs with a pending usn
+            self.mtime = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as i64);
+            self.usn = -1;
 
-        self.dcid = Some(trans.last_insert_rowid());
+            trans
+                .prepare_cached(include_str!("../sql/update_deckconfig.sql"))?
+                .execute(params![self.name, self.mtime, self.usn, conf_bytes, dcid])?;
+        } else {
+            // add deckconfig
+            trans
+                .prepare_cached(include_str!("../sql/add_deckconfig.sql"))?
+                .execute(params![
+                    self.dcid, self.name, self.mtime, self.usn, conf_bytes,
+                ])?;
+
+            self.dcid = Some(trans.last_insert_rowid());
+        }
 
         Ok(self.dcid.unwrap())
     }
@@ -17,6 +17,9 @@ pub enum Error {
     Decode(Box<dyn std::error::Error + Send + Sync>),
     #[error(transparent)]
     JSON(Box<dyn std::error::Error + Send + Sync>),
+    /// filtered decks have no deck config and cannot hold the cards of a note
+    #[error("Deck '{0}' is a filtered deck")]
+    FilteredDeck(String),
 }
 
 impl From<rusqlite::Error> for Error {
@@ -5,17 +5,53 @@ mod error;
 mod grave;
 mod note;
 mod notetype;
+mod tag;
 mod text;
 
 pub use deck::AnkiDeck;
+pub use deckconfig::{DeckConfig, DeckConfigInnerProto};
 pub use error::Error;
 pub use note::Note;
-pub use notetype::Notetype;
+pub use notetype::{Notetype, NotetypeChange};
+pub use tag::remove_unused_tags;
 
-use rusqlite::Connection;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn check_db_compatibility(conn: &mut Connection) -> Result<bool, Error> {
     let trans = conn.transaction()?;
     let ver: i32 = trans.query_row("SELECT ver FROM col", [], |row| row.get(0))?;
     Ok(ver == 18) // compatibility is only ensured for db scheme 18
 }
+
+/// Marks a change of the notetype schema, after which anki requires a full sync.
+pub fn set_schema_modified(trans: &Transaction) -> Result<(), Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64);
+    trans.execute("UPDATE col SET scm = ?1, mod = ?1", [now])?;
+    Ok(())
+}
+
+/// Reserves `count` positions at the end of the new card queue of the collection and returns the
+/// first one.
+pub fn reserve_new_positions(trans: &Transaction, count: u32) -> Result<u32, Error> {
+    let first: u32 = trans
+        .query_row("SELECT val FROM config WHERE KEY = 'nextPos'", [], |row| {
+            Ok(std::str::from_utf8(row.get_ref(0)?.as_bytes()?)
+                .ok()
+                .and_then(|val| val.trim().parse().ok()))
+        })
+        .optional()?
+        .flatten()
+        .unwrap_or(0);
+
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    trans.execute(
+        "INSERT OR REPLACE INTO config (KEY, usn, mtime_secs, val) VALUES ('nextPos', -1, ?, ?)",
+        params![mtime, first.saturating_add(count).to_string().into_bytes()],
+    )?;
+    Ok(first)
+}
@@ -1,12 +1,22 @@
 // Code in this file is based on or derived from the Anki project.
 // You can find the original code at https://github.com/ankitects/anki.
 
-use rusqlite::{params, Transaction};
+use lazy_static::lazy_static;
+use regex::Regex;
+use rusqlite::{params, OptionalExtension, Transaction};
 use sha1::{Digest, Sha1};
+use std::collections::{BTreeSet, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::grave::{add_grave, GraveKind};
+use crate::tag::register_tags;
 use crate::text::strip_html_preserving_media_filenames;
-use crate::{card::Card, deck::AnkiDeck, notetype::Notetype, Error};
+use crate::{
+    card::{Card, CardType},
+    deck::AnkiDeck,
+    notetype::Notetype,
+    Error,
+};
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Note {
@@ -66,6 +76,14 @@ impl Note {
         Ok(Some(note))
     }
 
+    /// Returns the ids of all notes of the notetype in ascending order.
+    pub fn ids_of_notetype(ntid: i64, trans: &Transaction) -> Result<Vec<i64>, Error> {
+        Ok(trans
+            .prepare_cached("SELECT id FROM notes WHERE mid = ? ORDER BY id")?
+            .query_map([ntid], |row| row.get(0))?
+            .collect::<Result<Vec<i64>, _>>()?)
+    }
+
     pub fn load_cards(&mut self, trans: &Transaction) -> Result<(), Error> {
         assert!(
             self.nid.is_some(),
@@ -90,20 +108,43 @@ impl Note {
         self
     }
 
+    pub fn with_guid(mut self, guid: String) -> Self {
+        self.guid = guid;
+        self
+    }
+
+    pub fn with_tags(mut self, tags: Vec<String>) -> Self {
+        self.tags = tags;
+        self
+    }
+
     pub fn with_field_entry(mut self, entry: &str) -> Self {
         self.fields.push(entry.to_string());
         self
     }
 
-    pub fn generate_cards(mut self, nt: &Notetype, deck: &AnkiDeck) -> Self {
+    /// Generates the cards of the note; only cards missing in the database are written for notes
+    /// which already exist.
+    pub fn generate_cards(self, nt: &Notetype, deck: &AnkiDeck) -> Self {
+        let template_idxs: Vec<u16> = if nt.is_cloze() {
+            self.cloze_ordinals()
+        } else {
+            (0..nt.num_templates()).map(|t| t as u16).collect()
+        };
+        self.generate_cards_for_templates(deck, &template_idxs)
+    }
+
+    /// Like `generate_cards`, but only for the given templates of a normal notetype.
+    pub fn generate_cards_for_templates(mut self, deck: &AnkiDeck, template_idxs: &[u16]) -> Self {
         let did = deck.get_id().expect("deck must be written to database");
 
         self.cards = Some(
-            (0..nt.num_templates())
+            template_idxs
+                .iter()
                 .map(|t| Card {
                     nid: self.nid,
                     did,
-                    template_idx: t.try_into().unwrap(),
+                    template_idx: *t,
                     ..Default::default()
                 })
                 .collect(),
@@ -112,6 +153,33 @@ impl Note {
         self
     }
 
+    /// Returns the card ordinals of the cloze numbers `{{c1::...}}` in the fields; a cloze note
+    /// without clozes still has its first card, like in anki.
+    fn cloze_ordinals(&self) -> Vec<u16> {
+        let mut ordinals: BTreeSet<u16> = self
+            .fields
+            .iter()
+            .flat_map(|field| CLOZE_RE.captures_iter(field))
+            .filter_map(|cap| cap[1].parse::<u16>().ok())
+            .filter(|n| *n > 0)
+            .map(|n| n - 1)
+            .collect();
+        if ordinals.is_empty() {
+            ordinals.insert(0);
+        }
+        ordinals.into_iter().collect()
+    }
+
+    pub fn with_new_position(mut self, position: u32) -> Self {
+        // the due number of new cards is their position in the new queue
+        if let Some(ref mut cards) = self.cards {
+            for card in cards.iter_mut().filter(|c| c.ctype == CardType::New) {
+                card.due = position as i32;
+            }
+        }
+        self
+    }
+
     pub fn write_to_db(&mut self, trans: &Transaction) -> Result<i64, Error> {
         let field1_nohtml = strip_html_preserving_media_filenames(&self.fields[0]);
         let checksum = field_checksum(field1_nohtml.as_ref());
@@ -119,8 +187,17 @@ impl Note {
         // always sort by first field
         let sort_field = field1_nohtml;
 
+        register_tags(trans, &self.tags)?;
+
         if let Some(nid) = self.nid {
-            // update note
+            // update note, anki syncs notes with a pending usn; the modification time changes with
+            // every update, so that an update can be told apart from the previous version
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as i64);
+            self.mtime = now.max(self.mtime + 1);
+            self.usn = -1;
+
             let mut stmt = trans.prepare_cached(include_str!("../sql/update_note.sql"))?;
 
             stmt.execute(params![
@@ -134,6 +211,20 @@ impl Note {
                 checksum,
                 nid
             ])?;
+
+            // cards of new templates or cloze numbers
+            if let Some(ref mut cards) = self.cards {
+                let existing = trans
+                    .prepare_cached("SELECT ord FROM cards WHERE nid = ?")?
+                    .query_map([nid], |row| row.get(0))?
+                    .collect::<Result<HashSet<u16>, _>>()?;
+                for card in cards.iter_mut() {
+                    if card.cid.is_none() && !existing.contains(&card.template_idx) {
+                        card.nid = Some(nid);
+                        card.write_to_db(trans)?;
+                    }
+                }
+            }
         } else {
             // create note
             let mut stmt = trans.prepare_cached(include_str!("../sql/add_note.sql"))?;
@@ -170,6 +261,62 @@ impl Note {
         self.nid.clone()
     }
 
+    pub fn get_guid(&self) -> &str {
+        &self.guid
+    }
+
+    pub fn get_mtime(&self) -> i64 {
+        self.mtime
+    }
+
+    pub fn get_notetype_id(&self) -> i64 {
+        self.ntid
+    }
+
+    pub fn get_fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    pub fn get_tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    /// Returns the deck of the first card of the note; cards in filtered decks count for their
+    /// original deck.
+    pub fn deck_id(&self, trans: &Transaction) -> Result<Option<i64>, Error> {
+        let Some(nid) = self.nid else {
+            return Ok(None);
+        };
+        Ok(trans
+            .prepare_cached(
+                "SELECT CASE WHEN odid != 0 THEN odid ELSE did END FROM cards WHERE nid = ? ORDER BY ord LIMIT 1",
+            )?
+            .query_row([nid], |row| row.get(0))
+            .optional()?)
+    }
+
+    /// Moves all cards of the note to another deck without changing their scheduling.
+    pub fn move_cards(&self, did: i64, trans: &Transaction) -> Result<(), Error> {
+        if let Some(nid) = self.nid {
+            let mtime = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as i64);
+
+            // cards in filtered decks return to their original deck
+            trans
+                .prepare_cached(
+                    "UPDATE cards SET did = ?, mod = ?, usn = -1 WHERE nid = ? AND odid = 0",
+                )?
+                .execute(params![did, mtime, nid])?;
+            trans
+                .prepare_cached(
+                    "UPDATE cards SET odid = ?, mod = ?, usn = -1 WHERE nid = ? AND odid != 0",
+                )?
+                .execute(params![did, mtime, nid])?;
+        }
+        Ok(())
+    }
+
     pub fn delete_with_cards(mut self, trans: &Transaction) -> Result<(), Error> {
         // do nothing if not written to db
         if let Some(nid) = self.nid {
@@ -190,6 +337,10 @@ impl Note {
     }
 }
 
+lazy_static! {
+    static ref CLOZE_RE: Regex = Regex::new(r"\{\{c(\d+)::").unwrap();
+}
+
 fn base91_u64() -> String {
     anki_base91(rand::random())
 }
@@ -3,14 +3,27 @@
 
 use prost::Message;
 use rusqlite::{params, OptionalExtension, Transaction};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub use anki_proto::notetypes::notetype::{
-    field::Config as FieldConfigProto, template::Config as TemplateConfigProto,
-    Config as ConfigProto,
+    config::Kind as KindProto, field::Config as FieldConfigProto,
+    template::Config as TemplateConfigProto, Config as ConfigProto,
 };
 
 use crate::Error;
 
+/// How a notetype differs from the one it is updated to.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum NotetypeChange {
+    Unchanged,
+    /// templates or styling changed
+    Changed,
+    /// fields or templates were added, which requires a full sync
+    SchemaChanged,
+    /// the fields differ in a way which would require moving the content of existing notes
+    Incompatible,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Notetype {
     ntid: Option<i64>,
@@ -93,6 +106,28 @@ impl Notetype {
             .map_err(Error::from)
     }
 
+    /// Makes this a cloze notetype, which has one card per cloze number instead of one per template.
+    pub fn with_cloze(mut self) -> Self {
+        self.config.kind = KindProto::Cloze as i32;
+        self
+    }
+
+    pub fn is_cloze(&self) -> bool {
+        self.config.kind == KindProto::Cloze as i32
+    }
+
+    pub fn load_by_name(name: &str, trans: &Transaction) -> Result<Option<Self>, Error> {
+        let ntid: Option<i64> = trans
+            .prepare_cached("SELECT id FROM notetypes WHERE name = ?")?
+            .query_row(params![name], |row| row.get(0))
+            .optional()?;
+
+        match ntid {
+            Some(ntid) => Self::load(ntid, trans),
+            None => Ok(None),
+        }
+    }
+
     pub fn with_field(mut self, name: &str) -> Self {
         self.fields.push(Field {
             name: name.to_string(),
@@ -134,10 +169,102 @@ impl Notetype {
         self
     }
 
+    pub fn with_css(mut self, css: &str) -> Self {
+        self.config.css = css.to_string();
+        self
+    }
+
+    pub fn get_css(&self) -> &str {
+        &self.config.css
+    }
+
+    pub fn field_names(&self) -> Vec<&str> {
+        self.fields.iter().map(|f| f.name.as_str()).collect()
+    }
+
+    pub fn num_fields(&self) -> usize {
+        self.fields.len()
+    }
+
     pub fn num_templates(&self) -> usize {
         self.templates.len()
     }
 
+    pub fn has_template(&self, name: &str) -> bool {
+        self.templates.iter().any(|t| t.name == name)
+    }
+
+    pub fn template_ord(&self, name: &str) -> Option<u16> {
+        self.templates
+            .iter()
+            .position(|t| t.name == name)
+            .map(|ord| ord as u16)
+    }
+
+    /// Takes over the templates and styling of `desired` and appends its new fields and templates.
+    /// Existing fields and templates keep their position, which the fields of the notes and the
+    /// ordinals of their cards refer to, and the ones missing in `desired` are kept. Nothing is
+    /// changed if the fields of `desired` do not start with the existing ones.
+    pub fn update_from(&mut self, desired: &Notetype) -> NotetypeChange {
+        let extends_fields = self
+            .fields
+            .iter()
+            .zip(&desired.fields)
+            .all(|(field, desired_field)| field.name == desired_field.name);
+        if !extends_fields {
+            return NotetypeChange::Incompatible;
+        }
+
+        let mut fields = self.fields.clone();
+        fields.extend(desired.fields.iter().skip(self.fields.len()).cloned());
+
+        let mut templates = Vec::new();
+        for existing in &self.templates {
+            let Some(template) = desired.templates.iter().find(|t| t.name == existing.name) else {
+                templates.push(existing.clone());
+                continue;
+            };
+
+            let mut template_config = existing.config.clone();
+            template_config.q_format = template.config.q_format.clone();
+            template_config.a_format = template.config.a_format.clone();
+
+            if template_config == existing.config {
+                templates.push(existing.clone());
+            } else {
+                templates.push(Template {
+                    mtime: now(),
+                    usn: -1,
+                    name: existing.name.clone(),
+                    config: template_config,
+                });
+            }
+        }
+        for template in &desired.templates {
+            if !self.has_template(&template.name) {
+                templates.push(template.clone());
+            }
+        }
+
+        let mut config = self.config.clone();
+        config.css = desired.config.css.clone();
+        config.latex_pre = desired.config.latex_pre.clone();
+        config.latex_post = desired.config.latex_post.clone();
+
+        let schema_changed =
+            fields.len() != self.fields.len() || templates.len() != self.templates.len();
+        let changed = self.templates != templates || self.config != config;
+        self.fields = fields;
+        self.templates = templates;
+        self.config = config;
+
+        match (schema_changed, changed) {
+            (true, _) => NotetypeChange::SchemaChanged,
+            (false, true) => NotetypeChange::Changed,
+            (false, false) => NotetypeChange::Unchanged,
+        }
+    }
+
     fn default_config() -> ConfigProto {
         ConfigProto {
             css: include_str!("../templates/notetype_css.txt").to_string(),
@@ -153,7 +280,10 @@ impl Notetype {
         self.config.encode(&mut config_bytes)?;
 
         if let Some(ntid) = self.ntid {
-            // update notetype
+            // update notetype, anki syncs notetypes with a pending usn
+            self.mtime = now();
+            self.usn = -1;
+
             trans
                 .prepare_cached(include_str!("../sql/update_notetype.sql"))?
                 .execute(params![self.name, self.mtime, self.usn, config_bytes, ntid])?;
@@ -212,4 +342,14 @@ impl Notetype {
     pub fn get_id(&self) -> Option<i64> {
         self.ntid
     }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
 }
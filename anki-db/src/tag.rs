@@ -0,0 +1,42 @@
+// Code in this file is based on or derived from the Anki project.
+// You can find the original code at https://github.com/ankitects/anki.
+
+use rusqlite::params;
+use rusqlite::Transaction;
+
+use crate::Error;
+
+/// Adds the tags and their parents (`a` for `a::b`) to the tags table of the collection.
+pub(crate) fn register_tags(trans: &Transaction, tags: &[String]) -> Result<(), Error> {
+    let mut stmt = trans.prepare_cached(include_str!("../sql/add_tag.sql"))?;
+    for tag in tags {
+        for (i, _) in tag.match_indices("::") {
+            stmt.execute(params![&tag[..i]])?;
+        }
+        stmt.execute(params![tag])?;
+    }
+    Ok(())
+}
+
+/// Removes the tags which are no longer used by any note or as a parent of a used tag.
+pub fn remove_unused_tags(trans: &Transaction, tags: &[String]) -> Result<(), Error> {
+    let mut used = trans.prepare_cached(
+        "SELECT EXISTS (SELECT 1 FROM notes WHERE tags LIKE '% ' || ?1 || ' %' ESCAPE '\\' OR tags LIKE '% ' || ?1 || '::%' ESCAPE '\\')",
+    )?;
+    let mut delete = trans.prepare_cached("DELETE FROM tags WHERE tag = ?")?;
+    for tag in tags {
+        // parents are checked after their children
+        let parents = tag.rmatch_indices("::").map(|(i, _)| i);
+        for end in std::iter::once(tag.len()).chain(parents) {
+            let name = &tag[..end];
+            let pattern = name
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            if !used.query_row(params![pattern], |row| row.get::<_, bool>(0))? {
+                delete.execute(params![name])?;
+            }
+        }
+    }
+    Ok(())
+}